@@ -1 +1,124 @@
+//! Endpoints for operators, nested under
+//! [`ADMIN_PATH`](crate::server::client_auth::ADMIN_PATH) and only reachable
+//! with a verified client certificate.
 
+use crate::database::Database;
+use crate::discord_bot::jobs::queue;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use tracing::warn;
+
+pub(crate) fn router(database: Arc<Database>) -> Router {
+    Router::new()
+        .route("/jobs/dead", get(list_dead_letters))
+        .route("/jobs/dead/retry", post(retry_dead_letter))
+        .with_state(database)
+}
+
+/// Jobs that failed too often, one per line with their ID, attempts and
+/// last error.
+async fn list_dead_letters(State(database): State<Arc<Database>>) -> Response {
+    let jobs = match queue::dead_letters(&database).await {
+        Ok(jobs) => jobs,
+        Err(err) => return internal_error(err),
+    };
+
+    let mut text = String::new();
+    for job in jobs {
+        let _ = writeln!(
+            text,
+            "{}\t{}\t{}",
+            job.id(),
+            job.attempts(),
+            job.last_error().unwrap_or("")
+        );
+    }
+
+    text.into_response()
+}
+
+/// Queues the dead letter given as `?id=` to run again right away.
+async fn retry_dead_letter(
+    State(database): State<Arc<Database>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let Some(id) = params.get("id").and_then(|id| id.parse().ok()) else {
+        return (StatusCode::BAD_REQUEST, "missing or invalid id").into_response();
+    };
+
+    match queue::retry_dead_letter(&database, id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+fn internal_error(err: anyhow::Error) -> Response {
+    warn!("admin request failed: {:#}", err);
+
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+#[cfg(all(test, feature = "rdb"))]
+mod tests {
+    use crate::admin_panel::router;
+    use crate::database::Database;
+    use crate::discord_bot::jobs::queue::{claim_due, dead_letters, enqueue, fail};
+    use crate::discord_bot::jobs::{Job, JobAction};
+    use crate::types::util::DateTime;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn lists_and_retries_dead_letters() {
+        let database_dir =
+            std::env::temp_dir().join(format!("genny-admin-{:016x}", rand::random::<u64>()));
+        let database = Arc::new(Database::open_rocksdb(&database_dir).unwrap());
+
+        let job = Job::new(JobAction::CheckAlerts(1), DateTime::now());
+        let job_id = job.id();
+        let mut trx = database.start_trx().unwrap();
+        enqueue(&mut trx, &job).unwrap();
+        trx.commit().await.unwrap();
+        // Far enough ahead for every backoff to have passed.
+        let mut now = DateTime::now();
+        while dead_letters(&database).await.unwrap().is_empty() {
+            now = now.saturating_add(Duration::from_secs(24 * 60 * 60));
+            let job = claim_due(&database, &now, 1).await.unwrap().pop().unwrap();
+            fail(&database, job, anyhow::anyhow!("boom")).await.unwrap();
+        }
+
+        let request = Request::get("/jobs/dead").body(Body::empty()).unwrap();
+        let response = router(database.clone()).oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with(&job_id.to_string()), "{}", body);
+        assert!(body.contains("boom"), "{}", body);
+
+        let request = Request::post(format!("/jobs/dead/retry?id={}", job_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = router(database.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(dead_letters(&database).await.unwrap().is_empty());
+
+        let request = Request::post(format!("/jobs/dead/retry?id={}", job_id))
+            .body(Body::empty())
+            .unwrap();
+        let response = router(database).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let _ = std::fs::remove_dir_all(&database_dir);
+    }
+}
@@ -1,30 +1,103 @@
 use anyhow::{anyhow, Context};
-use clap::{Args, Parser};
+use clap::{Args, Parser, ValueEnum};
 use rkyv::{Archive, Deserialize, Serialize};
-use rustls::internal::msgs::handshake::CertificateChain;
-use rustls::ServerConfig;
 use rustls_pki_types::pem::PemObject;
-use rustls_pki_types::{pem, CertificateDer, PrivateKeyDer};
-use std::convert::Infallible;
-use std::fs::File;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use crate::server::client_addr::IpNetwork;
+use crate::server::redirect::DEFAULT_HSTS_MAX_AGE_SECS;
 use crate::Result;
 
-/// Temporary. Will remove once ACME is ready.
-#[derive(Args, Archive, Serialize, Deserialize)]
+/// A private key and certificate chain, DER encoded.
+#[derive(Archive, Serialize, Deserialize)]
 pub(super) struct TlsConfig {
-    #[arg(long, requires = "tls_pub_cert", value_parser = get_priv_key_from_file)]
     pub(crate) tls_priv_key: Option<Vec<u8>>,
-    #[arg(long, requires = "tls_priv_key", value_parser = get_pub_cert_chain_from_file)]
     pub(crate) tls_pub_cert: Option<Vec<Vec<u8>>>,
 }
 
-impl TlsConfig {
+/// A certificate read from PEM files, for when ACME can't be used. The files
+/// are read again whenever they change.
+#[derive(Args, Clone)]
+pub(super) struct TlsFiles {
+    #[arg(long, requires = "tls_pub_cert", value_parser = check_priv_key_file)]
+    pub(crate) tls_priv_key: Option<PathBuf>,
+    #[arg(long, requires = "tls_priv_key", group = "certificate", value_parser = check_pub_cert_chain_file)]
+    pub(crate) tls_pub_cert: Option<PathBuf>,
+}
+
+/// Which challenge proves control of the domains to the ACME CA.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum AcmeChallenge {
+    /// Served on the HTTP listeners, which must be reachable on port 80.
+    #[value(name = "http-01")]
+    Http01,
+    /// Answered during the TLS handshake on the HTTPS listeners, which must
+    /// be reachable on port 443.
+    #[value(name = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// Obtains the certificate from an ACME CA instead of reading it from files.
+/// Creating the account agrees to the CA's terms of service.
+#[derive(Args)]
+pub(super) struct AcmeConfig {
+    /// A domain to put on the certificate; may be repeated.
+    #[arg(long = "acme-domain", group = "certificate")]
+    pub(crate) acme_domains: Vec<String>,
+    /// The directory of the CA. To test against a local Pebble, use
+    /// `https://localhost:14000/dir` and trust its root with `SSL_CERT_FILE`.
+    #[arg(long, default_value = "https://acme-v02.api.letsencrypt.org/directory")]
+    pub(crate) acme_directory_url: String,
+    /// A contact for the account, such as `mailto:admin@example.com`; may be repeated.
+    #[arg(long = "acme-contact")]
+    pub(crate) acme_contacts: Vec<String>,
+    #[arg(long, value_enum, default_value_t = AcmeChallenge::Http01)]
+    pub(crate) acme_challenge: AcmeChallenge,
+}
+
+impl AcmeConfig {
+    pub(super) fn is_some(&self) -> bool {
+        !self.acme_domains.is_empty()
+    }
+}
+
+impl TlsFiles {
     pub(super) fn is_some(&self) -> bool {
         self.tls_priv_key.is_some() && self.tls_pub_cert.is_some()
     }
+
+    pub(crate) fn read(&self) -> Result<TlsConfig> {
+        let priv_key_path = self
+            .tls_priv_key
+            .as_ref()
+            .context("TLS private key not provided")?;
+        let pub_cert_path = self
+            .tls_pub_cert
+            .as_ref()
+            .context("TLS public certificate not provided")?;
+
+        Ok(TlsConfig {
+            tls_priv_key: Some(get_priv_key_from_file(&priv_key_path.to_string_lossy())?),
+            tls_pub_cert: Some(get_pub_cert_chain_from_file(
+                &pub_cert_path.to_string_lossy(),
+            )?),
+        })
+    }
+}
+
+/// Fails on startup rather than on first use if the file isn't a key.
+fn check_priv_key_file(path: &str) -> Result<PathBuf> {
+    get_priv_key_from_file(path)?;
+
+    Ok(PathBuf::from(path))
+}
+
+fn check_pub_cert_chain_file(path: &str) -> Result<PathBuf> {
+    get_pub_cert_chain_from_file(path)?;
+
+    Ok(PathBuf::from(path))
 }
 
 fn get_priv_key_from_file(path: &str) -> Result<Vec<u8>> {
@@ -56,37 +129,59 @@ fn get_pub_cert_chain_from_file(path: &str) -> Result<Vec<Vec<u8>>> {
 
 #[derive(Args)]
 pub(super) struct BindConfig {
-    #[arg(long, requires = "tls_pub_cert")]
+    #[arg(long, requires = "certificate")]
     pub(super) https_socket: Vec<SocketAddr>,
     #[arg(long)]
     pub(super) http_socket: Vec<SocketAddr>,
+    /// Serves plain HTTP on a Unix socket at this path, e.g. for a reverse
+    /// proxy. Sockets passed by systemd socket activation are used as well.
+    #[cfg(unix)]
+    #[arg(long)]
+    pub(super) http_unix_socket: Vec<PathBuf>,
+    /// Also serves HTTP/3 over QUIC on the UDP ports of the HTTPS sockets.
+    #[arg(long, requires = "https_socket")]
+    pub(super) http3: bool,
+    /// How long requests and background tasks may take to finish on
+    /// shutdown before connections are closed, in seconds.
+    #[arg(long, default_value_t = 30)]
+    pub(super) shutdown_timeout_secs: u64,
+    /// Serves everything on the HTTP sockets instead of redirecting to HTTPS
+    /// when HTTPS sockets are bound too.
+    #[arg(long)]
+    pub(super) no_https_redirect: bool,
+    /// The port to redirect to, if HTTPS is reachable on a different port
+    /// than it's bound to, e.g. behind port forwarding.
+    #[arg(long)]
+    pub(super) https_redirect_port: Option<u16>,
+    /// How long browsers should only use HTTPS, in seconds. 0 leaves out the
+    /// `Strict-Transport-Security` header.
+    #[arg(long, default_value_t = DEFAULT_HSTS_MAX_AGE_SECS)]
+    pub(super) hsts_max_age_secs: u64,
+    /// Extends HSTS to every subdomain.
+    #[arg(long)]
+    pub(super) hsts_include_subdomains: bool,
+    /// Asks HTTPS clients for a certificate issued by a CA in this PEM file.
+    /// Admin endpoints then require one; all other paths stay open.
+    #[arg(long, requires = "certificate", value_parser = check_pub_cert_chain_file)]
+    pub(super) tls_client_ca: Option<PathBuf>,
+    /// Expects a PROXY protocol v1 or v2 header on every TCP and Unix socket
+    /// connection, as sent by load balancers, and takes the client's address
+    /// from it. Connections without one are closed.
+    #[arg(long)]
+    pub(super) proxy_protocol: bool,
+    /// Takes the client's address from `X-Forwarded-For` on requests from
+    /// these addresses or networks, e.g. `10.0.0.0/8`. Add `127.0.0.1` for a
+    /// proxy on a Unix socket.
+    #[arg(long)]
+    pub(super) trusted_proxy: Vec<IpNetwork>,
 }
 
-impl TryFrom<TlsConfig> for ServerConfig {
-    type Error = anyhow::Error;
-
-    fn try_from(tls_config: TlsConfig) -> Result<Self, Self::Error> {
-        let Some(priv_key_der_bytes) = tls_config.tls_priv_key else {
-            todo!()
-        };
-        let Some(pub_cert_der_bytes) = tls_config.tls_pub_cert else {
-            todo!()
-        };
-
-        let priv_key = PrivateKeyDer::try_from(priv_key_der_bytes).map_err(|err| anyhow!(err))?;
-
-        let mut cert_chain = vec![];
-        for cert_bytes in pub_cert_der_bytes {
-            // Infallible
-            let cert = CertificateDer::try_from(cert_bytes)?;
-            cert_chain.push(cert);
-        }
-
-        let server_config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, priv_key)?;
-
-        Ok(server_config)
+impl BindConfig {
+    pub(super) fn client_ca_bundle(&self) -> Result<Option<Vec<Vec<u8>>>> {
+        self.tls_client_ca
+            .as_ref()
+            .map(|path| get_pub_cert_chain_from_file(&path.to_string_lossy()))
+            .transpose()
     }
 }
 
@@ -95,5 +190,7 @@ pub(super) struct CliConfig {
     #[command(flatten)]
     pub(super) bind_config: BindConfig,
     #[command(flatten)]
-    pub(super) tls_config: TlsConfig,
+    pub(super) tls_files: TlsFiles,
+    #[command(flatten)]
+    pub(super) acme_config: AcmeConfig,
 }
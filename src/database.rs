@@ -1,22 +1,162 @@
-use crate::database::kv_stores::types::AsKey;
 use crate::Result;
+use std::fmt::{Display, Formatter};
+use std::ops::AsyncFnMut;
+use tracing::debug;
 
-mod models;
+pub(crate) use kv_stores::types::{AsKey, ToValue};
+
+pub(crate) mod models;
 
 #[cfg(feature = "kv_stores")]
 mod kv_stores;
 
-enum Database {}
+#[cfg(feature = "kv_stores")]
+pub(crate) type Transaction = kv_stores::KvTransaction;
+
+/// How often [`Database::transact`] runs a transaction that keeps conflicting.
+const MAX_TRANSACTION_ATTEMPTS: u32 = 10;
+
+/// Another transaction changed what a transaction read before it could
+/// commit. Running it again from the start may succeed.
+#[derive(Debug)]
+pub(crate) struct TransactionConflict;
+
+impl Display for TransactionConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transaction conflicted with another one")
+    }
+}
+
+impl std::error::Error for TransactionConflict {}
+
+pub(crate) enum Database {
+    #[cfg(feature = "kv_stores")]
+    KvStore(kv_stores::KvStore),
+}
 
 impl Database {
-    pub(crate) fn start_trx(&self) {
+    #[cfg(feature = "rdb")]
+    pub(crate) fn open_rocksdb(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        kv_stores::KvStore::open_rocksdb(path).map(Database::KvStore)
+    }
+
+    pub(crate) fn start_trx(&self) -> Result<Transaction> {
+        match self {
+            #[cfg(feature = "kv_stores")]
+            Database::KvStore(store) => store.start_trx(),
+        }
+    }
 
+    /// Runs `body` in a new transaction and commits it, starting over if it
+    /// conflicts with another transaction. `body` may therefore run more than
+    /// once and must not have effects outside the transaction.
+    pub(crate) async fn transact<T>(
+        &self,
+        mut body: impl AsyncFnMut(&mut Transaction) -> Result<T>,
+    ) -> Result<T> {
+        let mut attempts = 1;
+        loop {
+            let mut trx = self.start_trx()?;
+            let value = body(&mut trx).await?;
+
+            match trx.commit().await {
+                Ok(()) => return Ok(value),
+                Err(err)
+                    if err.is::<TransactionConflict>() && attempts < MAX_TRANSACTION_ATTEMPTS =>
+                {
+                    debug!("transaction conflicted, running it again");
+                    attempts += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
     }
 }
 
-pub(crate) trait DbModel: Sized {
+pub(crate) trait DbModel: ToValue {
     const KEYSPACE: &'static [u8];
     type Key: AsKey;
 
-    async fn get(key: Self::Key) -> Result<Self>;
+    fn key(&self) -> Self::Key;
+
+    async fn get(trx: &Transaction, key: &Self::Key) -> Result<Option<Self>> {
+        let full_key = [Self::KEYSPACE, &key.as_key()].concat();
+
+        match trx.get(&full_key).await? {
+            Some(bytes) => Ok(Some(Self::from_value(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns every model in the keyspace whose key starts with `key_prefix`.
+    async fn get_all(trx: &Transaction, key_prefix: &[u8]) -> Result<Vec<Self>> {
+        let prefix = [Self::KEYSPACE, key_prefix].concat();
+        let mut models = vec![];
+
+        for (_, bytes) in trx.get_prefix(&prefix).await? {
+            models.push(Self::from_value(&bytes)?);
+        }
+
+        Ok(models)
+    }
+
+    fn put(&self, trx: &mut Transaction) -> Result<()> {
+        let full_key = [Self::KEYSPACE, &self.key().as_key()].concat();
+        trx.set(&full_key, &self.to_value()?);
+
+        Ok(())
+    }
+
+    fn delete(trx: &mut Transaction, key: &Self::Key) {
+        let full_key = [Self::KEYSPACE, &key.as_key()].concat();
+        trx.clear(&full_key);
+    }
+}
+
+#[cfg(all(test, feature = "rdb"))]
+mod tests {
+    use crate::database::{Database, TransactionConflict};
+
+    #[tokio::test]
+    async fn conflicting_transactions_run_again() {
+        let database_dir =
+            std::env::temp_dir().join(format!("genny-trx-{:016x}", rand::random::<u64>()));
+        let database = Database::open_rocksdb(&database_dir).unwrap();
+
+        // Both read the counter before either commits, so the second one
+        // would lose the first one's increment.
+        let mut first = database.start_trx().unwrap();
+        let mut second = database.start_trx().unwrap();
+        assert_eq!(first.get(b"counter").await.unwrap(), None);
+        assert_eq!(second.get_prefix(b"count").await.unwrap(), vec![]);
+        first.set(b"counter", &[1]);
+        second.set(b"counter", &[1]);
+        first.commit().await.unwrap();
+        let err = second.commit().await.unwrap_err();
+        assert!(err.is::<TransactionConflict>(), "{:#}", err);
+
+        let mut runs = 0;
+        database
+            .transact(async |trx| {
+                runs += 1;
+                let counter = trx.get(b"counter").await?.unwrap()[0];
+                if runs == 1 {
+                    // Another writer commits between our read and commit.
+                    let mut other = database.start_trx()?;
+                    other.set(b"counter", &[counter + 1]);
+                    other.commit().await?;
+                }
+                trx.set(b"counter", &[counter + 1]);
+
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(runs, 2);
+        let trx = database.start_trx().unwrap();
+        assert_eq!(trx.get(b"counter").await.unwrap(), Some(vec![3]));
+        drop(trx);
+
+        let _ = std::fs::remove_dir_all(&database_dir);
+    }
 }
@@ -1,9 +1,94 @@
 mod backends;
 pub(super) mod types;
 
+use crate::database::kv_stores::types::prefix_end;
+use crate::Result;
+#[cfg(feature = "rdb")]
+use std::path::Path;
+
 pub(crate) enum KvStore {
     #[cfg(feature = "fdb")]
     FoundationDB(backends::fdb::FdbBackend),
     #[cfg(feature = "rdb")]
     RocksDB(backends::rdb::RdbStore),
 }
+
+pub(crate) enum KvTransaction {
+    #[cfg(feature = "fdb")]
+    FoundationDB(backends::fdb::FdbTransaction),
+    #[cfg(feature = "rdb")]
+    RocksDB(backends::rdb::RdbTransaction),
+}
+
+impl KvStore {
+    #[cfg(feature = "rdb")]
+    pub(crate) fn open_rocksdb(path: impl AsRef<Path>) -> Result<Self> {
+        backends::rdb::RdbStore::open(path).map(KvStore::RocksDB)
+    }
+
+    pub(crate) fn start_trx(&self) -> Result<KvTransaction> {
+        match self {
+            #[cfg(feature = "fdb")]
+            KvStore::FoundationDB(backend) => backend.start_trx().map(KvTransaction::FoundationDB),
+            #[cfg(feature = "rdb")]
+            KvStore::RocksDB(store) => store.start_trx().map(KvTransaction::RocksDB),
+        }
+    }
+}
+
+impl KvTransaction {
+    pub(crate) async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            #[cfg(feature = "fdb")]
+            KvTransaction::FoundationDB(trx) => trx.get(key).await,
+            #[cfg(feature = "rdb")]
+            KvTransaction::RocksDB(trx) => trx.get(key).await,
+        }
+    }
+
+    pub(crate) fn set(&mut self, key: &[u8], value: &[u8]) {
+        match self {
+            #[cfg(feature = "fdb")]
+            KvTransaction::FoundationDB(trx) => trx.set(key, value),
+            #[cfg(feature = "rdb")]
+            KvTransaction::RocksDB(trx) => trx.set(key, value),
+        }
+    }
+
+    pub(crate) fn clear(&mut self, key: &[u8]) {
+        match self {
+            #[cfg(feature = "fdb")]
+            KvTransaction::FoundationDB(trx) => trx.clear(key),
+            #[cfg(feature = "rdb")]
+            KvTransaction::RocksDB(trx) => trx.clear(key),
+        }
+    }
+
+    /// Returns the key-value pairs from `begin` (inclusive) to `end` (exclusive) in key order.
+    pub(crate) async fn get_range(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            #[cfg(feature = "fdb")]
+            KvTransaction::FoundationDB(trx) => trx.get_range(begin, end, limit).await,
+            #[cfg(feature = "rdb")]
+            KvTransaction::RocksDB(trx) => trx.get_range(begin, end, limit).await,
+        }
+    }
+
+    pub(crate) async fn get_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.get_range(prefix, &prefix_end(prefix), None).await
+    }
+
+    pub(crate) async fn commit(self) -> Result<()> {
+        match self {
+            #[cfg(feature = "fdb")]
+            KvTransaction::FoundationDB(trx) => trx.commit().await,
+            #[cfg(feature = "rdb")]
+            KvTransaction::RocksDB(trx) => trx.commit().await,
+        }
+    }
+}
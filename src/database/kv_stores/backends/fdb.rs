@@ -1,5 +1,82 @@
-use foundationdb::{api::NetworkAutoStop, Database, Transaction};
+use crate::database::TransactionConflict;
+use crate::Result;
+use foundationdb::{api::NetworkAutoStop, Database, RangeOption, Transaction};
+use futures::TryStreamExt;
 
 pub(crate) struct FdbBackend {
     guard: NetworkAutoStop,
+    db: Database,
+}
+
+impl FdbBackend {
+    /// Starts the FoundationDB network thread and opens the cluster.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once per process, see [`foundationdb::boot`].
+    pub(crate) unsafe fn boot(cluster_file: Option<&str>) -> Result<Self> {
+        let guard = foundationdb::boot();
+        let db = Database::new(cluster_file)?;
+
+        Ok(Self { guard, db })
+    }
+
+    pub(crate) fn start_trx(&self) -> Result<FdbTransaction> {
+        let trx = self.db.create_trx()?;
+
+        Ok(FdbTransaction { trx })
+    }
+}
+
+pub(crate) struct FdbTransaction {
+    trx: Transaction,
+}
+
+impl FdbTransaction {
+    pub(crate) async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.trx.get(key, false).await?;
+
+        Ok(value.map(|slice| slice.to_vec()))
+    }
+
+    pub(crate) fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.trx.set(key, value);
+    }
+
+    pub(crate) fn clear(&mut self, key: &[u8]) {
+        self.trx.clear(key);
+    }
+
+    pub(crate) async fn get_range(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = RangeOption {
+            limit,
+            ..RangeOption::from((begin.to_vec(), end.to_vec()))
+        };
+        let key_values = self
+            .trx
+            .get_ranges_keyvalues(range, false)
+            .map_ok(|kv| (kv.key().to_vec(), kv.value().to_vec()))
+            .try_collect()
+            .await?;
+
+        Ok(key_values)
+    }
+
+    /// Fails with [`TransactionConflict`] if the commit may succeed when the
+    /// transaction is run again, e.g. after a conflict with another instance.
+    pub(crate) async fn commit(self) -> Result<()> {
+        match self.trx.commit().await {
+            Ok(_) => Ok(()),
+            // Waits out FoundationDB's backoff if the error is retryable.
+            Err(err) => match err.on_error().await {
+                Ok(_) => Err(TransactionConflict.into()),
+                Err(err) => Err(err.into()),
+            },
+        }
+    }
 }
@@ -1 +1,157 @@
-pub(crate) struct RdbStore {}
+use crate::database::TransactionConflict;
+use crate::Result;
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// RocksDB holds an exclusive lock on its directory, so only a single instance
+/// can ever use the store. Transactions buffer their writes and apply them as
+/// one write batch on commit, after checking that nothing they read has
+/// changed in the meantime.
+pub(crate) struct RdbStore {
+    db: Arc<DB>,
+    /// Held from checking a transaction's reads until its writes are applied,
+    /// so no other commit can slip in between.
+    commit_lock: Arc<Mutex<()>>,
+}
+
+impl RdbStore {
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let db = DB::open_default(path)?;
+
+        Ok(Self {
+            db: Arc::new(db),
+            commit_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub(crate) fn start_trx(&self) -> Result<RdbTransaction> {
+        Ok(RdbTransaction {
+            db: self.db.clone(),
+            commit_lock: self.commit_lock.clone(),
+            writes: BTreeMap::new(),
+            reads: Mutex::default(),
+        })
+    }
+}
+
+pub(crate) struct RdbTransaction {
+    db: Arc<DB>,
+    commit_lock: Arc<Mutex<()>>,
+    /// Pending writes, `None` being a deletion. Kept so reads see our own writes.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    reads: Mutex<Reads>,
+}
+
+/// What a transaction read from the database, as it was when read.
+#[derive(Default)]
+struct Reads {
+    keys: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ranges: Vec<(Vec<u8>, Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>)>,
+}
+
+impl RdbTransaction {
+    pub(crate) async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(pending) = self.writes.get(key) {
+            return Ok(pending.clone());
+        }
+
+        let value = self.db.get(key)?;
+        self.reads()
+            .keys
+            .entry(key.to_vec())
+            .or_insert_with(|| value.clone());
+
+        Ok(value)
+    }
+
+    pub(crate) fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub(crate) fn clear(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    pub(crate) async fn get_range(
+        &self,
+        begin: &[u8],
+        end: &[u8],
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let stored = read_range(&self.db, begin, end)?;
+        let mut key_values: BTreeMap<_, _> = stored.iter().cloned().collect();
+        self.reads()
+            .ranges
+            .push((begin.to_vec(), end.to_vec(), stored));
+
+        for (key, pending) in self.writes.range(begin.to_vec()..end.to_vec()) {
+            match pending {
+                Some(value) => key_values.insert(key.clone(), value.clone()),
+                None => key_values.remove(key),
+            };
+        }
+
+        Ok(key_values
+            .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// Fails with [`TransactionConflict`] if another transaction changed
+    /// anything this one read since it was read.
+    pub(crate) async fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        let _commit_guard = self
+            .commit_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let reads = self
+            .reads
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (key, value) in reads.keys {
+            if self.db.get(&key)? != value {
+                return Err(TransactionConflict.into());
+            }
+        }
+        for (begin, end, key_values) in reads.ranges {
+            if read_range(&self.db, &begin, &end)? != key_values {
+                return Err(TransactionConflict.into());
+            }
+        }
+
+        let mut batch = WriteBatch::default();
+        for (key, pending) in self.writes {
+            match pending {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        self.db.write(batch)?;
+
+        Ok(())
+    }
+
+    fn reads(&self) -> MutexGuard<'_, Reads> {
+        self.reads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn read_range(db: &DB, begin: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut key_values = vec![];
+
+    for item in db.iterator(IteratorMode::From(begin, Direction::Forward)) {
+        let (key, value) = item?;
+        if &*key >= end {
+            break;
+        }
+        key_values.push((key.to_vec(), value.to_vec()));
+    }
+
+    Ok(key_values)
+}
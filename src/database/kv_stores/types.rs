@@ -1,3 +1,78 @@
-pub(crate) trait AsKey {}
+use crate::types::util::DateTime;
+use crate::Result;
+use anyhow::anyhow;
+use rkyv::api::high::{HighDeserializer, HighSerializer, HighValidator};
+use rkyv::bytecheck::CheckBytes;
+use rkyv::rancor;
+use rkyv::ser::allocator::ArenaHandle;
+use rkyv::util::AlignedVec;
+use rkyv::{Archive, Deserialize, Serialize};
 
-pub(crate) trait ToValue {}
+pub(crate) trait AsKey {
+    /// Encodes the key so that byte-wise ordering matches the logical ordering.
+    fn as_key(&self) -> Vec<u8>;
+}
+
+impl AsKey for u64 {
+    fn as_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+}
+
+impl AsKey for DateTime {
+    fn as_key(&self) -> Vec<u8> {
+        // Flipping the sign bit makes negative timestamps sort before positive ones.
+        ((self.timestamp_millis() as u64) ^ (1 << 63))
+            .to_be_bytes()
+            .to_vec()
+    }
+}
+
+impl AsKey for Vec<u8> {
+    fn as_key(&self) -> Vec<u8> {
+        self.clone()
+    }
+}
+
+pub(crate) trait ToValue: Sized {
+    fn to_value(&self) -> Result<Vec<u8>>;
+
+    fn from_value(bytes: &[u8]) -> Result<Self>;
+}
+
+impl<T> ToValue for T
+where
+    T: Archive + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Error>>,
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+        + Deserialize<T, HighDeserializer<rancor::Error>>,
+{
+    fn to_value(&self) -> Result<Vec<u8>> {
+        let bytes = rkyv::to_bytes::<rancor::Error>(self)
+            .map_err(|err| anyhow!("failed to serialize value: {}", err))?;
+
+        Ok(bytes.to_vec())
+    }
+
+    fn from_value(bytes: &[u8]) -> Result<Self> {
+        // Values read from the store are not guaranteed to be aligned.
+        let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+        aligned.extend_from_slice(bytes);
+
+        rkyv::from_bytes::<T, rancor::Error>(&aligned)
+            .map_err(|err| anyhow!("failed to deserialize value: {}", err))
+    }
+}
+
+/// Returns the first key after every key starting with `prefix`.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+
+    // The prefix is empty or all 0xFF, so everything after it is in range.
+    vec![u8::MAX]
+}
@@ -1 +1,161 @@
-use rkyv::{Archive, Deserialize, Serialize};
+use crate::database::{AsKey, DbModel, Transaction};
+use crate::discord_bot::alerts::{AlertRules, AlertState};
+use crate::discord_bot::jobs::GeneratorList;
+use crate::discord_bot::permissions::GuildPermissions;
+use crate::discord_bot::reminders::{
+    ReminderState, ReminderStateKey, Subscription, SubscriptionKey, UserSettings,
+};
+use crate::types::coordinates::ArkMap;
+use crate::types::server::{AcmeAccount, TlsCert};
+use crate::types::tracking::TekGenerator;
+use crate::Result;
+
+/// Generators are grouped by guild and then by ARK server so a single prefix
+/// read returns everything a list message has to show.
+pub(crate) struct GeneratorKey {
+    pub(crate) guild_id: u64,
+    pub(crate) server: String,
+    pub(crate) id: u64,
+}
+
+impl GeneratorKey {
+    pub(crate) fn server_prefix(guild_id: u64, server: &str) -> Vec<u8> {
+        let mut prefix = guild_id.as_key();
+        prefix.extend_from_slice(server.as_bytes());
+        // Terminates the server name so "foo" doesn't match "foobar".
+        prefix.push(0);
+
+        prefix
+    }
+}
+
+impl AsKey for GeneratorKey {
+    fn as_key(&self) -> Vec<u8> {
+        let mut key = Self::server_prefix(self.guild_id, &self.server);
+        key.extend_from_slice(&self.id.as_key());
+
+        key
+    }
+}
+
+impl TekGenerator {
+    /// Every generator on `map` in an ARK server, the ones running out first first.
+    pub(crate) async fn get_on_map(
+        trx: &Transaction,
+        guild_id: u64,
+        server: &str,
+        map: ArkMap,
+    ) -> Result<Vec<Self>> {
+        let prefix = GeneratorKey::server_prefix(guild_id, server);
+        let mut generators = Self::get_all(trx, &prefix).await?;
+        generators.retain(|generator| generator.map() == map);
+        generators.sort_by_key(|generator| generator.current_fuel().runs_out_at());
+
+        Ok(generators)
+    }
+}
+
+impl DbModel for TekGenerator {
+    const KEYSPACE: &'static [u8] = b"tekgen/";
+    type Key = GeneratorKey;
+
+    fn key(&self) -> Self::Key {
+        GeneratorKey {
+            guild_id: self.guild_id(),
+            server: self.server().to_string(),
+            id: self.id(),
+        }
+    }
+}
+
+impl DbModel for GeneratorList {
+    const KEYSPACE: &'static [u8] = b"genlist/";
+    type Key = Vec<u8>;
+
+    fn key(&self) -> Self::Key {
+        self.list_id.clone()
+    }
+}
+
+impl DbModel for AlertRules {
+    const KEYSPACE: &'static [u8] = b"alertrules/";
+    type Key = u64;
+
+    fn key(&self) -> Self::Key {
+        self.guild_id
+    }
+}
+
+impl DbModel for AlertState {
+    const KEYSPACE: &'static [u8] = b"alertstate/";
+    type Key = GeneratorKey;
+
+    fn key(&self) -> Self::Key {
+        self.generator_key()
+    }
+}
+
+impl DbModel for UserSettings {
+    const KEYSPACE: &'static [u8] = b"usersettings/";
+    type Key = u64;
+
+    fn key(&self) -> Self::Key {
+        self.user_id
+    }
+}
+
+impl DbModel for Subscription {
+    const KEYSPACE: &'static [u8] = b"subs/";
+    type Key = SubscriptionKey;
+
+    fn key(&self) -> Self::Key {
+        SubscriptionKey {
+            user_id: self.user_id,
+            guild_id: self.guild_id,
+            target: self.target.clone(),
+        }
+    }
+}
+
+impl DbModel for ReminderState {
+    const KEYSPACE: &'static [u8] = b"reminderstate/";
+    type Key = ReminderStateKey;
+
+    fn key(&self) -> Self::Key {
+        ReminderStateKey {
+            user_id: self.user_id,
+            generator: GeneratorKey {
+                guild_id: self.guild_id,
+                server: self.server.clone(),
+                id: self.generator_id,
+            },
+        }
+    }
+}
+
+impl DbModel for GuildPermissions {
+    const KEYSPACE: &'static [u8] = b"perms/";
+    type Key = u64;
+
+    fn key(&self) -> Self::Key {
+        self.guild_id
+    }
+}
+
+impl DbModel for TlsCert {
+    const KEYSPACE: &'static [u8] = b"tlscert/";
+    type Key = Vec<u8>;
+
+    fn key(&self) -> Self::Key {
+        Self::key_for(&self.domains)
+    }
+}
+
+impl DbModel for AcmeAccount {
+    const KEYSPACE: &'static [u8] = b"acmeaccount/";
+    type Key = Vec<u8>;
+
+    fn key(&self) -> Self::Key {
+        self.directory_url.as_bytes().to_vec()
+    }
+}
@@ -1,21 +1,65 @@
+pub(crate) mod alerts;
 mod commands;
-mod jobs;
+mod error;
+mod gateway;
+pub(crate) mod jobs;
+pub(crate) mod locale;
+pub(crate) mod outbound;
+pub(crate) mod permissions;
+pub(crate) mod reminders;
+pub(crate) mod render;
+mod replay;
+#[cfg(all(test, feature = "rdb"))]
+pub(crate) mod testing;
 
+use crate::database::Database;
+use crate::discord_bot::error::InteractionError;
+use crate::discord_bot::locale::tr;
+use crate::discord_bot::outbound::Outbound;
+use crate::discord_bot::replay::ReplayGuard;
+use crate::types::util::DateTime;
 use crate::Result;
+use anyhow::anyhow;
 use axum::body::Bytes;
 use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
-use axum::Json;
-use clap::Parser;
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use clap::{Parser, ValueEnum};
 use serenity::all::{
-    CreateInteractionResponse, CreateInteractionResponseMessage, Interaction, Verifier,
+    ApplicationId, CommandInteraction, ComponentInteraction, CreateAutocompleteResponse,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Interaction, ModalInteraction,
+    Verifier,
 };
-use serenity::http::Http;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{field, Instrument, Span};
 
 pub(super) struct DiscordBotState {
     verifier: Verifier,
-    http_client: Http,
+    replay_guard: ReplayGuard,
+    /// Every request to the Discord API goes through here, see [`outbound`].
+    outbound: Outbound,
+    database: Arc<Database>,
+    list_refresh_interval: Duration,
+    interactions_path: String,
+    metrics_path: Option<String>,
+    /// Tracks commands running after their response was deferred.
+    task_tracker: TaskTracker,
+    mode: BotMode,
+    bot_token: String,
+}
+
+/// How Discord delivers interactions to the bot.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum BotMode {
+    /// Discord sends them to the interactions endpoint, which must be publicly reachable.
+    Http,
+    /// The bot receives them over a gateway websocket, which works behind NAT.
+    Gateway,
 }
 
 #[derive(Parser)]
@@ -24,20 +68,106 @@ pub(crate) struct DiscordBotConfig {
     public_key: [u8; 32],
     #[arg(long, env = "GENNY_BOT_TOKEN")]
     bot_token: String,
+    /// Needed to register commands and answer interactions.
+    #[arg(long, env = "GENNY_APPLICATION_ID")]
+    application_id: u64,
+    #[arg(long, value_enum, default_value_t = BotMode::Http, env = "GENNY_BOT_MODE")]
+    mode: BotMode,
+    /// How often generator list messages are refreshed, in seconds.
+    #[arg(long, default_value_t = 60, env = "GENNY_LIST_REFRESH_SECS")]
+    list_refresh_secs: u64,
+    /// The path Discord delivers interactions to, as set in the developer portal.
+    #[arg(long, default_value = "/interactions", value_parser = parse_route_path, env = "GENNY_INTERACTIONS_PATH")]
+    interactions_path: String,
+    /// How far the signed timestamp of an interaction may be from now, in seconds.
+    #[arg(long, default_value_t = 60, env = "GENNY_INTERACTION_MAX_AGE_SECS")]
+    interaction_max_age_secs: u64,
+    /// Requests per second sent to Discord across all routes.
+    #[arg(long, default_value_t = outbound::GLOBAL_RATE_LIMIT, env = "GENNY_GLOBAL_RATE_LIMIT")]
+    global_rate_limit: u32,
+    /// Serves metrics of outgoing Discord requests at this path, if set.
+    #[arg(long, value_parser = parse_route_path, env = "GENNY_METRICS_PATH")]
+    metrics_path: Option<String>,
+    /// Sends Discord API requests to this base URL instead, such as a shared
+    /// ratelimiting proxy.
+    #[arg(long, env = "GENNY_DISCORD_API_PROXY")]
+    discord_api_proxy: Option<String>,
 }
 
 impl DiscordBotState {
-    pub(super) fn configure(config: DiscordBotConfig) -> Result<Self> {
+    pub(super) fn configure(config: DiscordBotConfig, database: Arc<Database>) -> Result<Self> {
         let verifier = Verifier::try_new(config.public_key)?;
-        let http_client = Http::new(&config.bot_token);
+        let outbound = Outbound::new(
+            &config.bot_token,
+            ApplicationId::new(config.application_id),
+            config.discord_api_proxy.as_deref(),
+            config.global_rate_limit,
+        );
+
+        let replay_guard = ReplayGuard::new(Duration::from_secs(config.interaction_max_age_secs));
 
         let state = Self {
             verifier,
-            http_client,
+            replay_guard,
+            outbound,
+            database,
+            list_refresh_interval: Duration::from_secs(config.list_refresh_secs),
+            interactions_path: config.interactions_path,
+            metrics_path: config.metrics_path,
+            task_tracker: TaskTracker::new(),
+            mode: config.mode,
+            bot_token: config.bot_token,
         };
 
         Ok(state)
     }
+
+    /// Builds the router serving the interactions endpoint, which is left
+    /// out in gateway mode, and the metrics endpoint if configured.
+    pub(super) fn router(self: Arc<Self>) -> Router {
+        let mut router = Router::new();
+        if self.mode == BotMode::Http {
+            router = router.route(&self.interactions_path, post(handle_interaction));
+        }
+        if let Some(metrics_path) = &self.metrics_path {
+            router = router.route(metrics_path, get(handle_metrics));
+        }
+
+        router.with_state(self)
+    }
+
+    /// Registers the application commands with Discord.
+    pub(super) async fn register_commands(&self) -> Result<()> {
+        commands::register(&self.outbound).await
+    }
+
+    /// Receives interactions over the gateway until cancelled. Returns
+    /// immediately in HTTP mode.
+    pub(super) async fn run_gateway(
+        self: Arc<Self>,
+        cancellation_token: CancellationToken,
+    ) -> Result<()> {
+        if self.mode != BotMode::Gateway {
+            return Ok(());
+        }
+        let bot_token = self.bot_token.clone();
+
+        gateway::run(self, &bot_token, cancellation_token).await
+    }
+
+    /// Runs the job queue worker until cancelled.
+    pub(super) async fn run_jobs(self: Arc<Self>, cancellation_token: CancellationToken) {
+        jobs::run_worker(self, cancellation_token).await;
+    }
+
+    /// Waits until cancelled, then for every deferred command to finish.
+    /// Run as a server background task, so commands deferred by requests
+    /// drained on shutdown still get their response.
+    pub(super) async fn finish_deferred(self: Arc<Self>, cancellation_token: CancellationToken) {
+        cancellation_token.cancelled().await;
+        self.task_tracker.close();
+        self.task_tracker.wait().await;
+    }
 }
 
 fn parse_str_to_hex(str: &str) -> Result<[u8; 32]> {
@@ -46,49 +176,457 @@ fn parse_str_to_hex(str: &str) -> Result<[u8; 32]> {
     Ok(bytes)
 }
 
-#[tracing::instrument(name = "Interaction", level = "trace", skip_all)]
+fn parse_route_path(str: &str) -> Result<String> {
+    if !str.starts_with('/') {
+        return Err(anyhow!("route path must start with '/': {}", str));
+    }
+
+    Ok(str.to_string())
+}
+
+#[tracing::instrument(
+    name = "Interaction",
+    level = "trace",
+    skip_all,
+    fields(id = field::Empty, kind = field::Empty)
+)]
 pub(super) async fn handle_interaction(
-    State(verifier): State<Arc<Verifier>>,
+    State(state): State<Arc<DiscordBotState>>,
     headers: HeaderMap,
     body: Bytes,
-) -> std::result::Result<Json<CreateInteractionResponse>, StatusCode> {
-    let signature_header = headers
+) -> std::result::Result<Json<CreateInteractionResponse>, InteractionError> {
+    let signature = headers
         .get("X-Signature-Ed25519")
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    let signature = signature_header
-        .to_str()
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
-
-    let timestamp_header = headers
+        .and_then(|header| header.to_str().ok())
+        .ok_or(InteractionError::MissingHeader("X-Signature-Ed25519"))?;
+    let timestamp = headers
         .get("X-Signature-Timestamp")
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    let timestamp = timestamp_header
-        .to_str()
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        .and_then(|header| header.to_str().ok())
+        .ok_or(InteractionError::MissingHeader("X-Signature-Timestamp"))?;
 
-    verifier
+    state
+        .verifier
         .verify(signature, timestamp, &body)
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        .map_err(|_| InteractionError::InvalidSignature)?;
 
-    let Json(interaction): Json<Interaction> =
-        Json::from_bytes(&body).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    // The timestamp is covered by the signature, so it can be trusted from here on.
+    let now = DateTime::now();
+    let timestamp_secs: i64 = timestamp
+        .parse()
+        .map_err(|_| InteractionError::InvalidTimestamp)?;
+    if !state.replay_guard.is_fresh(timestamp_secs, &now) {
+        return Err(InteractionError::StaleTimestamp);
+    }
 
-    let response = match interaction {
-        Interaction::Ping(c) => CreateInteractionResponse::Pong,
-        Interaction::Command(c) => {
-            todo!()
-        }
-        Interaction::Autocomplete(a) => {
-            todo!()
-        }
-        Interaction::Component(com) => {
-            todo!()
+    let Json(interaction): Json<Interaction> = Json::from_bytes(&body)
+        .map_err(|rejection| InteractionError::MalformedBody(rejection.body_text()))?;
+
+    let span = Span::current();
+    span.record("id", interaction.id().get());
+    span.record("kind", field::debug(interaction.kind()));
+
+    if !state
+        .replay_guard
+        .check_and_record(interaction.id().get(), &now)
+    {
+        return Err(InteractionError::Replayed);
+    }
+
+    let reply = InteractionReply::route(interaction).ok_or(InteractionError::UnsupportedType)?;
+    let response = reply.initial_response();
+    reply.spawn_deferred(&state);
+
+    Ok(response.into())
+}
+
+async fn handle_metrics(State(state): State<Arc<DiscordBotState>>) -> impl IntoResponse {
+    state.outbound.metrics().render()
+}
+
+/// How an interaction is answered, shared by the interactions endpoint and the gateway.
+enum InteractionReply {
+    Immediate(CreateInteractionResponse),
+    /// Acknowledged with a deferred response, then run in the background.
+    Deferred(CommandInteraction),
+}
+
+impl InteractionReply {
+    /// Returns `None` for interaction types the bot doesn't know.
+    fn route(interaction: Interaction) -> Option<Self> {
+        let reply = match interaction {
+            Interaction::Ping(_) => InteractionReply::Immediate(CreateInteractionResponse::Pong),
+            // Discord only waits 3 seconds for a response, which database
+            // transactions can exceed, so commands run in the background.
+            Interaction::Command(command) => InteractionReply::Deferred(command),
+            // No command options offer autocompletion yet.
+            Interaction::Autocomplete(_) => InteractionReply::Immediate(
+                CreateInteractionResponse::Autocomplete(CreateAutocompleteResponse::new()),
+            ),
+            Interaction::Component(ComponentInteraction { locale, .. })
+            | Interaction::Modal(ModalInteraction { locale, .. }) => {
+                let message = CreateInteractionResponseMessage::new()
+                    .content(tr!(&locale, "error.unsupported-interaction"))
+                    .ephemeral(true);
+                InteractionReply::Immediate(CreateInteractionResponse::Message(message))
+            }
+            _ => return None,
+        };
+
+        Some(reply)
+    }
+
+    fn initial_response(&self) -> CreateInteractionResponse {
+        match self {
+            InteractionReply::Immediate(response) => response.clone(),
+            InteractionReply::Deferred(command) => {
+                let deferred = CreateInteractionResponseMessage::new()
+                    .ephemeral(!commands::defers_publicly(command));
+                CreateInteractionResponse::Defer(deferred)
+            }
         }
-        Interaction::Modal(fz) => {
-            todo!()
+    }
+
+    fn spawn_deferred(self, state: &Arc<DiscordBotState>) {
+        if let InteractionReply::Deferred(command) = self {
+            let task = commands::run_deferred(state.clone(), command);
+            state.task_tracker.spawn(task.in_current_span());
         }
-        _ => todo!(),
+    }
+}
+
+#[cfg(all(test, feature = "rdb"))]
+mod tests {
+    use crate::database::{AsKey, DbModel};
+    use crate::discord_bot::alerts::{parse_thresholds, AlertRules, DEFAULT_THRESHOLDS};
+    use crate::discord_bot::error::InteractionError;
+    use crate::discord_bot::jobs::{queue, GeneratorList, Job, JobAction, JobOutcome};
+    use crate::discord_bot::reminders::{Subscription, SubscriptionTarget, UserSettings};
+    use crate::discord_bot::testing::{
+        gen_command_interaction, ping_interaction, tek_generator, TestBot, CHANNEL_ID, GUILD_ID,
+        USER_ID,
     };
+    use crate::types::util::DateTime;
+    use axum::http::Method;
+    use serde_json::json;
+    use std::time::Duration;
 
-    Ok(response.into())
+    #[tokio::test]
+    async fn ping_is_answered_with_pong() {
+        let bot = TestBot::start().await.unwrap();
+
+        let response = bot.interact(&ping_interaction(1)).await.unwrap();
+        assert_eq!(response["type"], 1);
+    }
+
+    #[tokio::test]
+    async fn forged_and_replayed_interactions_are_rejected() {
+        let bot = TestBot::start().await.unwrap();
+        let body = serde_json::to_vec(&ping_interaction(2)).unwrap();
+        let headers = bot.signer.sign(&body, DateTime::now().timestamp_secs());
+
+        let mut tampered = body.clone();
+        tampered.push(b' ');
+        assert!(matches!(
+            bot.send_signed(headers.clone(), tampered).await,
+            Err(InteractionError::InvalidSignature)
+        ));
+        assert!(bot.send_signed(headers.clone(), body.clone()).await.is_ok());
+        assert!(matches!(
+            bot.send_signed(headers, body).await,
+            Err(InteractionError::Replayed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn deferred_command_edits_the_original_response() {
+        let bot = TestBot::start().await.unwrap();
+        let options = json!([{
+            "name": "permissions",
+            "type": 2,
+            "options": [{ "name": "show", "type": 1 }],
+        }]);
+
+        let response = bot
+            .interact(&gen_command_interaction(3, options))
+            .await
+            .unwrap();
+        // A deferred response, edited once the command finishes.
+        assert_eq!(response["type"], 5);
+
+        bot.settle().await;
+        let edit = bot
+            .discord
+            .find(Method::PATCH, "token-3/messages/@original")
+            .expect("the deferred response is edited");
+        let content = edit.body["content"].as_str().unwrap();
+        assert!(content.contains("**Viewer**: everyone"), "{}", content);
+    }
+
+    #[tokio::test]
+    async fn map_is_shown_to_the_channel() {
+        let bot = TestBot::start().await.unwrap();
+        let options = json!([{
+            "name": "map",
+            "type": 1,
+            "options": [
+                { "name": "server", "type": 3, "value": "PvE" },
+                { "name": "map", "type": 3, "value": "Island" },
+            ],
+        }]);
+
+        let response = bot
+            .interact(&gen_command_interaction(6, options))
+            .await
+            .unwrap();
+        // 64 is the ephemeral flag.
+        assert_eq!(response["type"], 5);
+        assert_ne!(response["data"]["flags"], 64);
+
+        bot.settle().await;
+        assert!(bot
+            .discord
+            .find(Method::PATCH, "token-6/messages/@original")
+            .is_some());
+        assert!(bot.discord.find(Method::POST, "token-6").is_none());
+
+        let options = json!([{
+            "name": "permissions",
+            "type": 2,
+            "options": [{ "name": "show", "type": 1 }],
+        }]);
+        let response = bot
+            .interact(&gen_command_interaction(7, options))
+            .await
+            .unwrap();
+        assert_eq!(response["data"]["flags"], 64);
+    }
+
+    #[tokio::test]
+    async fn reminder_thresholds_are_set_per_user() {
+        let bot = TestBot::start().await.unwrap();
+        let options = json!([{
+            "name": "reminders",
+            "type": 2,
+            "options": [{
+                "name": "thresholds",
+                "type": 1,
+                "options": [{ "name": "thresholds", "type": 3, "value": "30m, 2h" }],
+            }],
+        }]);
+
+        bot.interact(&gen_command_interaction(4, options))
+            .await
+            .unwrap();
+        bot.settle().await;
+
+        let trx = bot.state.database.start_trx().unwrap();
+        let settings = UserSettings::get(&trx, &USER_ID).await.unwrap().unwrap();
+        assert_eq!(settings.thresholds, vec![2 * 60 * 60, 30 * 60]);
+    }
+
+    #[tokio::test]
+    async fn removed_generators_are_unsubscribed_by_id() {
+        let bot = TestBot::start().await.unwrap();
+        let mut trx = bot.state.database.start_trx().unwrap();
+        Subscription {
+            user_id: USER_ID,
+            guild_id: GUILD_ID,
+            target: SubscriptionTarget::Generator {
+                server: "PvE".to_string(),
+                id: 42,
+            },
+        }
+        .put(&mut trx)
+        .unwrap();
+        trx.commit().await.unwrap();
+
+        let options = json!([{
+            "name": "unsubscribe",
+            "type": 1,
+            "options": [
+                { "name": "server", "type": 3, "value": "PvE" },
+                { "name": "map", "type": 3, "value": "Island" },
+                { "name": "generator", "type": 3, "value": "42" },
+            ],
+        }]);
+        bot.interact(&gen_command_interaction(5, options))
+            .await
+            .unwrap();
+        bot.settle().await;
+
+        let trx = bot.state.database.start_trx().unwrap();
+        let subscriptions = Subscription::get_all(&trx, &USER_ID.as_key())
+            .await
+            .unwrap();
+        assert!(subscriptions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn generator_list_is_posted_and_kept_up_to_date() {
+        let bot = TestBot::start().await.unwrap();
+        let generator = tek_generator(1, "Base", 1, DateTime::now());
+        bot.state
+            .database
+            .transact(async |trx| generator.put(trx))
+            .await
+            .unwrap();
+
+        let options = json!([{
+            "name": "list",
+            "type": 1,
+            "options": [
+                { "name": "server", "type": 3, "value": "PvE" },
+                { "name": "map", "type": 3, "value": "Island" },
+            ],
+        }]);
+        bot.interact(&gen_command_interaction(8, options))
+            .await
+            .unwrap();
+        bot.settle().await;
+
+        let messages = format!("channels/{}/messages", CHANNEL_ID);
+        let posted = bot
+            .discord
+            .find(Method::POST, &messages)
+            .expect("the list is posted");
+        assert_eq!(posted.body["embeds"][0]["fields"][0]["name"], "Base");
+        // The list ID is the interaction ID.
+        let list_id = 8u64.to_be_bytes().to_vec();
+        let trx = bot.state.database.start_trx().unwrap();
+        let list = GeneratorList::get(&trx, &list_id)
+            .await
+            .unwrap()
+            .expect("the list is stored");
+        drop(trx);
+
+        // Generators added later show up on the next update.
+        let added = tek_generator(2, "Farm", 2, DateTime::now());
+        bot.state
+            .database
+            .transact(async |trx| added.put(trx))
+            .await
+            .unwrap();
+        let outcome = Job::new(JobAction::UpdateTimers(list_id), DateTime::now())
+            .run(&bot.state)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, JobOutcome::Reschedule(_)));
+
+        let edit = bot
+            .discord
+            .find(Method::PATCH, &format!("{}/{}", messages, list.message_id))
+            .expect("the list message is edited");
+        let names: Vec<&str> = edit.body["embeds"][0]["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["name"].as_str().unwrap())
+            .collect();
+        // The one running out first comes first.
+        assert_eq!(names, ["Base", "Farm"]);
+    }
+
+    #[tokio::test]
+    async fn alert_is_sent_once_when_a_generator_runs_low() {
+        let bot = TestBot::start().await.unwrap();
+        // One element lasts 18 hours, so half an hour is left.
+        let last_filled = DateTime::now().saturating_sub(Duration::from_secs(35 * 30 * 60));
+        let generator = tek_generator(1, "Base", 1, last_filled);
+        let check_job = Job::new(JobAction::CheckAlerts(GUILD_ID), DateTime::now());
+        let rules = AlertRules {
+            guild_id: GUILD_ID,
+            channel_id: 210,
+            role_id: Some(211),
+            thresholds: parse_thresholds(DEFAULT_THRESHOLDS).unwrap(),
+            check_job_id: check_job.id(),
+            locale: "en-US".to_string(),
+        };
+        bot.state
+            .database
+            .transact(async |trx| {
+                generator.put(trx)?;
+                rules.put(trx)?;
+                queue::enqueue(trx, &check_job)
+            })
+            .await
+            .unwrap();
+
+        let job = queue::claim_due(&bot.state.database, &DateTime::now(), 1)
+            .await
+            .unwrap()
+            .pop()
+            .expect("the checker is queued");
+        let outcome = job.run(&bot.state).await.unwrap();
+        assert!(matches!(outcome, JobOutcome::Reschedule(_)));
+
+        let alert = bot
+            .discord
+            .find(Method::POST, "channels/210/messages")
+            .expect("an alert is sent");
+        let content = alert.body["content"].as_str().unwrap();
+        assert!(content.starts_with("<@&211> **Base**"), "{}", content);
+        assert!(content.contains("less than 1h 0m"), "{}", content);
+
+        // The threshold already fired during this fuel cycle.
+        job.run(&bot.state).await.unwrap();
+        let alerts = bot
+            .discord
+            .requests()
+            .into_iter()
+            .filter(|request| request.path.ends_with("channels/210/messages"))
+            .count();
+        assert_eq!(alerts, 1);
+    }
+
+    #[tokio::test]
+    async fn reminder_is_sent_to_subscribers() {
+        let bot = TestBot::start().await.unwrap();
+        // One element lasts 18 hours, so half an hour is left.
+        let last_filled = DateTime::now().saturating_sub(Duration::from_secs(35 * 30 * 60));
+        let generator = tek_generator(1, "Base", 1, last_filled);
+        bot.state
+            .database
+            .transact(async |trx| generator.put(trx))
+            .await
+            .unwrap();
+
+        let options = json!([{
+            "name": "subscribe",
+            "type": 1,
+            "options": [
+                { "name": "server", "type": 3, "value": "PvE" },
+                { "name": "map", "type": 3, "value": "Island" },
+            ],
+        }]);
+        bot.interact(&gen_command_interaction(9, options))
+            .await
+            .unwrap();
+        bot.settle().await;
+
+        let job = queue::claim_due(&bot.state.database, &DateTime::now(), 1)
+            .await
+            .unwrap()
+            .pop()
+            .expect("subscribing starts the reminder checker");
+        let outcome = job.run(&bot.state).await.unwrap();
+        assert!(matches!(outcome, JobOutcome::Reschedule(_)));
+
+        assert!(bot
+            .discord
+            .find(Method::POST, "users/@me/channels")
+            .is_some());
+        let reminder = bot
+            .discord
+            .requests()
+            .into_iter()
+            .find(|request| {
+                request.method == Method::POST
+                    && request.path.starts_with("channels/")
+                    && request.path.ends_with("/messages")
+            })
+            .expect("a reminder is sent");
+        let content = reminder.body["content"].as_str().unwrap();
+        assert!(content.starts_with("**Base**"), "{}", content);
+        assert!(content.contains("less than 1h 0m"), "{}", content);
+    }
 }
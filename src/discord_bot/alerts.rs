@@ -0,0 +1,267 @@
+use crate::database::models::GeneratorKey;
+use crate::database::{AsKey, DbModel};
+use crate::discord_bot::jobs::JobOutcome;
+use crate::discord_bot::locale::{self, tr};
+use crate::discord_bot::render::{self, format_duration};
+use crate::discord_bot::DiscordBotState;
+use crate::types::tracking::TekGenerator;
+use crate::types::util::DateTime;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use rkyv::{Archive, Deserialize, Serialize};
+use serenity::all::{ChannelId, CreateAllowedMentions, CreateMessage, RoleId};
+use std::time::Duration;
+use tracing::debug;
+
+/// How often a guild's generators are checked against its alert thresholds.
+pub(crate) const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+pub(crate) const DEFAULT_THRESHOLDS: &str = "24h,6h,1h,empty";
+
+/// Where and when a guild wants to be told about generators running low.
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct AlertRules {
+    pub(crate) guild_id: u64,
+    pub(crate) channel_id: u64,
+    pub(crate) role_id: Option<u64>,
+    /// Remaining fuel in seconds at which to alert, in descending order. Zero means empty.
+    pub(crate) thresholds: Vec<u64>,
+    /// The only `CheckAlerts` job allowed to act on these rules, so replacing
+    /// the rules never leaves two checkers running.
+    pub(crate) check_job_id: u64,
+    /// The guild's locale when the rules were set.
+    pub(crate) locale: String,
+}
+
+/// Which thresholds already fired for a generator during its current fuel cycle.
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct AlertState {
+    pub(crate) guild_id: u64,
+    pub(crate) server: String,
+    pub(crate) generator_id: u64,
+    /// Identifies the fuel cycle. A refuel changes it and re-arms every threshold.
+    pub(crate) last_filled: DateTime,
+    pub(crate) fired: Vec<u64>,
+}
+
+impl AlertState {
+    pub(crate) fn generator_key(&self) -> GeneratorKey {
+        GeneratorKey {
+            guild_id: self.guild_id,
+            server: self.server.clone(),
+            id: self.generator_id,
+        }
+    }
+}
+
+/// Parses a comma-separated list such as `24h,6h,1h,empty` into seconds.
+pub(crate) fn parse_thresholds(input: &str) -> Result<Vec<u64>> {
+    let mut thresholds = vec![];
+
+    for part in input
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        if part.eq_ignore_ascii_case("empty") {
+            thresholds.push(0);
+            continue;
+        }
+
+        let unit_at = part.len() - part.chars().last().map_or(0, char::len_utf8);
+        let (value, unit) = part.split_at(unit_at);
+        let value: u64 = value
+            .parse()
+            .with_context(|| format!("invalid threshold: {}", part))?;
+        let unit_secs: u64 = match unit {
+            "d" => 24 * 60 * 60,
+            "h" => 60 * 60,
+            "m" => 60,
+            _ => return Err(anyhow!("invalid threshold unit in {}, use d, h or m", part)),
+        };
+        let seconds = value
+            .checked_mul(unit_secs)
+            .with_context(|| format!("threshold too large: {}", part))?;
+        thresholds.push(seconds);
+    }
+
+    if thresholds.is_empty() {
+        return Err(anyhow!("at least one threshold is required"));
+    }
+
+    thresholds.sort_unstable_by(|a, b| b.cmp(a));
+    thresholds.dedup();
+
+    Ok(thresholds)
+}
+
+pub(crate) fn format_threshold(locale: &str, threshold: u64) -> String {
+    match threshold {
+        0 => tr!(locale, "fuel.threshold-empty"),
+        secs => format_duration(Duration::from_secs(secs)),
+    }
+}
+
+/// Returns the most urgent threshold newly crossed by `remaining`, marking it
+/// and every less urgent one as fired so catching up after downtime sends a
+/// single alert instead of one per threshold.
+pub(crate) fn newly_crossed(
+    thresholds: &[u64],
+    fired: &mut Vec<u64>,
+    remaining: Duration,
+) -> Option<u64> {
+    let crossed: Vec<u64> = thresholds
+        .iter()
+        .copied()
+        .filter(|threshold| remaining.as_secs() <= *threshold && !fired.contains(threshold))
+        .collect();
+
+    fired.extend(&crossed);
+    // Thresholds are sorted in descending order.
+    crossed.last().copied()
+}
+
+/// Describes a generator that crossed `threshold`, for alerts and reminders alike.
+pub(crate) fn render_warning(locale: &str, generator: &TekGenerator, threshold: u64) -> String {
+    let runs_out = render::relative_timestamp(&generator.current_fuel().runs_out_at());
+    let map = locale::map_name(locale, generator.map());
+
+    if threshold == 0 {
+        tr!(
+            locale,
+            "fuel.ran-out",
+            name = generator.name(),
+            server = generator.server(),
+            map = map,
+            runs_out = runs_out
+        )
+    } else {
+        tr!(
+            locale,
+            "fuel.running-low",
+            name = generator.name(),
+            server = generator.server(),
+            map = map,
+            threshold = format_threshold(locale, threshold),
+            runs_out = runs_out
+        )
+    }
+}
+
+fn render_alert(rules: &AlertRules, generator: &TekGenerator, threshold: u64) -> String {
+    let mention = rules
+        .role_id
+        .map(|role_id| format!("<@&{}> ", role_id))
+        .unwrap_or_default();
+
+    mention + &render_warning(&rules.locale, generator, threshold)
+}
+
+/// Sends alerts for every generator in the guild that crossed a threshold.
+pub(crate) async fn check_alerts(
+    state: &DiscordBotState,
+    job_id: u64,
+    guild_id: u64,
+) -> Result<JobOutcome> {
+    let trx = state.database.start_trx()?;
+    let rules = match AlertRules::get(&trx, &guild_id).await? {
+        Some(rules) if rules.check_job_id == job_id => rules,
+        _ => {
+            debug!("alert rules removed or replaced, not rescheduling");
+            return Ok(JobOutcome::Finished);
+        }
+    };
+    let generators = TekGenerator::get_all(&trx, &guild_id.as_key()).await?;
+    let mut pending = vec![];
+
+    let now = DateTime::now();
+    for generator in generators {
+        let fuel = generator.current_fuel();
+        let mut alert_state = AlertState::get(&trx, &generator.key())
+            .await?
+            .filter(|alert_state| alert_state.last_filled == fuel.last_filled())
+            .unwrap_or_else(|| AlertState {
+                guild_id,
+                server: generator.server().to_string(),
+                generator_id: generator.id(),
+                last_filled: fuel.last_filled(),
+                fired: vec![],
+            });
+
+        if let Some(threshold) = newly_crossed(
+            &rules.thresholds,
+            &mut alert_state.fired,
+            fuel.remaining(&now),
+        ) {
+            let content = render_alert(&rules, &generator, threshold);
+            let embed = render::generator_embed(&rules.locale, &generator, &now);
+            pending.push((content, embed, alert_state));
+        }
+    }
+    // Transactions are short-lived, so nothing is held open while talking to Discord.
+    drop(trx);
+
+    let channel_id = ChannelId::new(rules.channel_id);
+    let allowed_mentions = CreateAllowedMentions::new().roles(rules.role_id.map(RoleId::new));
+
+    for (content, embed, alert_state) in pending {
+        let message = CreateMessage::new()
+            .content(content)
+            .embed(embed)
+            .allowed_mentions(allowed_mentions.clone());
+        state
+            .outbound
+            .send(|http| async move { channel_id.send_message(&http, message).await })
+            .await?;
+
+        // Recorded one by one, so a retry after a failure doesn't repeat sent alerts.
+        state
+            .database
+            .transact(async |trx| alert_state.put(trx))
+            .await?;
+    }
+
+    Ok(JobOutcome::Reschedule(CHECK_INTERVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::alerts::{newly_crossed, parse_thresholds};
+    use std::time::Duration;
+
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn parse_default_thresholds() {
+        let thresholds = parse_thresholds("1h, 24h,6h,empty,1h").unwrap();
+        assert_eq!(thresholds, vec![24 * HOUR, 6 * HOUR, HOUR, 0]);
+
+        assert!(parse_thresholds("").is_err());
+        assert!(parse_thresholds("6x").is_err());
+        assert!(parse_thresholds("999999999999999999d").is_err());
+    }
+
+    /// Each threshold fires once, and only the most urgent one when several are crossed.
+    #[test]
+    fn fire_once_per_cycle() {
+        let thresholds = vec![24 * HOUR, 6 * HOUR, HOUR, 0];
+        let mut fired = vec![];
+
+        assert_eq!(
+            newly_crossed(&thresholds, &mut fired, Duration::from_secs(30 * HOUR)),
+            None
+        );
+        assert_eq!(
+            newly_crossed(&thresholds, &mut fired, Duration::from_secs(5 * HOUR)),
+            Some(6 * HOUR)
+        );
+        assert_eq!(
+            newly_crossed(&thresholds, &mut fired, Duration::from_secs(4 * HOUR)),
+            None
+        );
+        assert_eq!(
+            newly_crossed(&thresholds, &mut fired, Duration::ZERO),
+            Some(0)
+        );
+        assert_eq!(fired.len(), thresholds.len());
+    }
+}
@@ -1 +1,1025 @@
+use crate::database::models::GeneratorKey;
+use crate::database::{AsKey, DbModel, Transaction};
+use crate::discord_bot::alerts::{self, AlertRules};
+use crate::discord_bot::error::UserError;
+use crate::discord_bot::jobs::{queue, GeneratorList, Job, JobAction};
+use crate::discord_bot::locale::{self, tr, DEFAULT_LOCALE};
+use crate::discord_bot::outbound::Outbound;
+use crate::discord_bot::permissions::{self, AccessLevel, GuildPermissions};
+use crate::discord_bot::reminders::{
+    QuietHours, Subscription, SubscriptionKey, SubscriptionTarget, UserSettings,
+};
+use crate::discord_bot::render;
+use crate::discord_bot::DiscordBotState;
+use crate::types::coordinates::ArkMap;
+use crate::types::tracking::TekGenerator;
+use crate::types::util::DateTime;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use serenity::all::{
+    Command, CommandInteraction, CommandOptionType, CreateAttachment, CreateCommand,
+    CreateCommandOption, CreateEmbed, CreateInteractionResponseFollowup, CreateMessage,
+    EditInteractionResponse, ResolvedOption, ResolvedValue,
+};
+use std::fmt::Display;
+use std::sync::Arc;
+use tracing::warn;
 
+/// Applies the name and description translations of `key` to `option`.
+fn localize(
+    mut option: CreateCommandOption,
+    key: &str,
+    args: &[(&str, &dyn Display)],
+) -> CreateCommandOption {
+    option = option.description(locale::translate(DEFAULT_LOCALE, key, args));
+    for (locale, description) in locale::localizations(key, args) {
+        option = option.description_localized(locale, description);
+    }
+    for (locale, name) in locale::localizations(&format!("{}.name", key), &[]) {
+        option = option.name_localized(locale, name);
+    }
+
+    option
+}
+
+fn option(kind: CommandOptionType, name: &str, key: &str) -> CreateCommandOption {
+    localize(CreateCommandOption::new(kind, name, ""), key, &[])
+}
+
+fn map_option(key: &str) -> CreateCommandOption {
+    let mut map_option = option(CommandOptionType::String, "map", key).required(true);
+    for map in ArkMap::ALL {
+        let name_key = format!("map.{}", map.choice_value());
+        map_option = map_option.add_string_choice_localized(
+            locale::map_name(DEFAULT_LOCALE, map),
+            map.choice_value(),
+            locale::localizations(&name_key, &[]),
+        );
+    }
+
+    map_option
+}
+
+fn server_option() -> CreateCommandOption {
+    option(CommandOptionType::String, "server", "cmd.option.server").required(true)
+}
+
+fn gen_command() -> CreateCommand {
+    let list_subcommand = option(CommandOptionType::SubCommand, "list", "cmd.gen.list")
+        .add_sub_option(server_option())
+        .add_sub_option(map_option("cmd.gen.list.map"));
+
+    let map_subcommand = option(CommandOptionType::SubCommand, "map", "cmd.gen.map")
+        .add_sub_option(server_option())
+        .add_sub_option(map_option("cmd.gen.map.map"));
+
+    let alerts_group = option(
+        CommandOptionType::SubCommandGroup,
+        "alerts",
+        "cmd.gen.alerts",
+    )
+    .add_sub_option(
+        option(CommandOptionType::SubCommand, "set", "cmd.gen.alerts.set")
+            .add_sub_option(
+                option(
+                    CommandOptionType::Channel,
+                    "channel",
+                    "cmd.gen.alerts.set.channel",
+                )
+                .required(true),
+            )
+            .add_sub_option(option(
+                CommandOptionType::Role,
+                "role",
+                "cmd.gen.alerts.set.role",
+            ))
+            .add_sub_option(localize(
+                CreateCommandOption::new(CommandOptionType::String, "thresholds", ""),
+                "cmd.gen.alerts.set.thresholds",
+                &[("default", &alerts::DEFAULT_THRESHOLDS as &dyn Display)],
+            )),
+    )
+    .add_sub_option(option(
+        CommandOptionType::SubCommand,
+        "disable",
+        "cmd.gen.alerts.disable",
+    ));
+
+    let generator_option = option(
+        CommandOptionType::String,
+        "generator",
+        "cmd.option.generator",
+    );
+    let subscribe_subcommand = option(
+        CommandOptionType::SubCommand,
+        "subscribe",
+        "cmd.gen.subscribe",
+    )
+    .add_sub_option(server_option())
+    .add_sub_option(map_option("cmd.option.generator-map"))
+    .add_sub_option(generator_option.clone());
+    let unsubscribe_subcommand = option(
+        CommandOptionType::SubCommand,
+        "unsubscribe",
+        "cmd.gen.unsubscribe",
+    )
+    .add_sub_option(server_option())
+    .add_sub_option(map_option("cmd.option.generator-map"))
+    .add_sub_option(generator_option);
+
+    let reminders_group = option(
+        CommandOptionType::SubCommandGroup,
+        "reminders",
+        "cmd.gen.reminders",
+    )
+    .add_sub_option(
+        option(
+            CommandOptionType::SubCommand,
+            "quiet-hours",
+            "cmd.gen.reminders.quiet-hours",
+        )
+        .add_sub_option(
+            option(
+                CommandOptionType::Integer,
+                "start",
+                "cmd.gen.reminders.quiet-hours.start",
+            )
+            .min_int_value(0)
+            .max_int_value(23)
+            .required(true),
+        )
+        .add_sub_option(
+            option(
+                CommandOptionType::Integer,
+                "end",
+                "cmd.gen.reminders.quiet-hours.end",
+            )
+            .min_int_value(0)
+            .max_int_value(23)
+            .required(true),
+        )
+        .add_sub_option(
+            option(
+                CommandOptionType::Integer,
+                "utc-offset",
+                "cmd.gen.reminders.quiet-hours.utc-offset",
+            )
+            .min_int_value(-12)
+            .max_int_value(14),
+        ),
+    )
+    .add_sub_option(
+        option(
+            CommandOptionType::SubCommand,
+            "thresholds",
+            "cmd.gen.reminders.thresholds",
+        )
+        .add_sub_option(
+            localize(
+                CreateCommandOption::new(CommandOptionType::String, "thresholds", ""),
+                "cmd.gen.reminders.thresholds.thresholds",
+                &[("default", &alerts::DEFAULT_THRESHOLDS as &dyn Display)],
+            )
+            .required(true),
+        ),
+    )
+    .add_sub_option(option(
+        CommandOptionType::SubCommand,
+        "off",
+        "cmd.gen.reminders.off",
+    ))
+    .add_sub_option(option(
+        CommandOptionType::SubCommand,
+        "on",
+        "cmd.gen.reminders.on",
+    ));
+
+    let mut level_option =
+        option(CommandOptionType::String, "level", "cmd.option.level").required(true);
+    for level in AccessLevel::ALL {
+        let name_key = format!("access.{}", level.choice_value());
+        level_option = level_option.add_string_choice_localized(
+            locale::access_level_name(DEFAULT_LOCALE, level),
+            level.choice_value(),
+            locale::localizations(&name_key, &[]),
+        );
+    }
+    let role_option = option(CommandOptionType::Role, "role", "cmd.option.role").required(true);
+
+    let permissions_group = option(
+        CommandOptionType::SubCommandGroup,
+        "permissions",
+        "cmd.gen.permissions",
+    )
+    .add_sub_option(
+        option(
+            CommandOptionType::SubCommand,
+            "grant",
+            "cmd.gen.permissions.grant",
+        )
+        .add_sub_option(level_option.clone())
+        .add_sub_option(role_option.clone()),
+    )
+    .add_sub_option(
+        option(
+            CommandOptionType::SubCommand,
+            "revoke",
+            "cmd.gen.permissions.revoke",
+        )
+        .add_sub_option(level_option)
+        .add_sub_option(role_option),
+    )
+    .add_sub_option(option(
+        CommandOptionType::SubCommand,
+        "show",
+        "cmd.gen.permissions.show",
+    ));
+
+    let mut command = CreateCommand::new("gen")
+        .description(tr!(DEFAULT_LOCALE, "cmd.gen"))
+        .dm_permission(false);
+    for (locale, description) in locale::localizations("cmd.gen", &[]) {
+        command = command.description_localized(locale, description);
+    }
+
+    command
+        .add_option(list_subcommand)
+        .add_option(map_subcommand)
+        .add_option(alerts_group)
+        .add_option(subscribe_subcommand)
+        .add_option(unsubscribe_subcommand)
+        .add_option(reminders_group)
+        .add_option(permissions_group)
+}
+
+pub(super) async fn register(outbound: &Outbound) -> Result<()> {
+    outbound
+        .send(|http| async move { Command::set_global_commands(&http, vec![gen_command()]).await })
+        .await?;
+
+    Ok(())
+}
+
+async fn dispatch(state: &DiscordBotState, command: &CommandInteraction) -> Result<Reply> {
+    let mut path = vec![command.data.name.as_str()];
+    let mut options = command.data.options();
+
+    // Walks down subcommand groups and subcommands to the options of the leaf.
+    loop {
+        match options.first() {
+            Some(ResolvedOption {
+                name,
+                value:
+                    ResolvedValue::SubCommandGroup(sub_options) | ResolvedValue::SubCommand(sub_options),
+                ..
+            }) => {
+                path.push(*name);
+                options = sub_options.clone();
+            }
+            _ => break,
+        }
+    }
+
+    if let ["gen", subcommand_path @ ..] = path.as_slice() {
+        permissions::authorize(state, command, subcommand_path).await?;
+    }
+
+    match path.as_slice() {
+        ["gen", "list"] => gen_list(state, command, &options).await,
+        ["gen", "map"] => gen_map(state, command, &options).await,
+        ["gen", "alerts", "set"] => gen_alerts_set(state, command, &options).await,
+        ["gen", "alerts", "disable"] => gen_alerts_disable(state, command).await,
+        ["gen", "subscribe"] => gen_subscribe(state, command, &options, true).await,
+        ["gen", "unsubscribe"] => gen_subscribe(state, command, &options, false).await,
+        ["gen", "reminders", "quiet-hours"] => {
+            gen_reminders_quiet_hours(state, command, &options).await
+        }
+        ["gen", "reminders", "thresholds"] => {
+            gen_reminders_thresholds(state, command, &options).await
+        }
+        ["gen", "reminders", "off"] => gen_reminders_toggle(state, command, false).await,
+        ["gen", "reminders", "on"] => gen_reminders_toggle(state, command, true).await,
+        ["gen", "permissions", "grant"] => {
+            gen_permissions_update(state, command, &options, true).await
+        }
+        ["gen", "permissions", "revoke"] => {
+            gen_permissions_update(state, command, &options, false).await
+        }
+        ["gen", "permissions", "show"] => gen_permissions_show(state, command).await,
+        _ => Err(anyhow!("unknown command: /{}", path.join(" "))),
+    }
+}
+
+/// What a command shows the user once it finishes.
+pub(super) struct Reply {
+    content: String,
+    embeds: Vec<CreateEmbed>,
+    attachments: Vec<CreateAttachment>,
+    ephemeral: bool,
+}
+
+impl Reply {
+    pub(super) fn ephemeral(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+            embeds: vec![],
+            attachments: vec![],
+            ephemeral: true,
+        }
+    }
+
+    pub(super) fn public(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+            embeds: vec![],
+            attachments: vec![],
+            ephemeral: false,
+        }
+    }
+
+    pub(super) fn with_embed(mut self, embed: CreateEmbed) -> Self {
+        self.embeds.push(embed);
+        self
+    }
+
+    /// Attaches a file, which embeds can show as `attachment://<file name>`.
+    pub(super) fn with_attachment(mut self, attachment: CreateAttachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+}
+
+/// Whether the deferred response to `command` is shown to the whole channel,
+/// as for commands whose output is meant for everyone there.
+pub(super) fn defers_publicly(command: &CommandInteraction) -> bool {
+    command.data.name == "gen"
+        && command
+            .data
+            .options
+            .first()
+            .is_some_and(|option| option.name == "map")
+}
+
+/// Runs a command whose response was deferred, then delivers its reply.
+#[tracing::instrument(
+    name = "Command",
+    level = "trace",
+    skip_all,
+    fields(name = %command.data.name, user = command.user.id.get())
+)]
+pub(super) async fn run_deferred(state: Arc<DiscordBotState>, command: CommandInteraction) {
+    let reply = match dispatch(&state, &command).await {
+        Ok(reply) => reply,
+        Err(err) => match err.downcast_ref::<UserError>() {
+            Some(user_error) => Reply::ephemeral(user_error.to_string()),
+            None => {
+                warn!("failed to handle /{}: {:#}", command.data.name, err);
+                Reply::ephemeral(tr!(&command.locale, "error.internal"))
+            }
+        },
+    };
+
+    if let Err(err) = deliver(&state, &command, reply).await {
+        warn!(
+            "failed to send the reply to /{}: {:#}",
+            command.data.name, err
+        );
+    }
+}
+
+/// Replies shown to the same audience as the deferred response replace it.
+/// Others, such as errors of a public command, are sent as a follow-up and
+/// the deferred response is removed, since its visibility can't change.
+async fn deliver(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    reply: Reply,
+) -> Result<()> {
+    let outbound = &state.outbound;
+
+    if reply.ephemeral != defers_publicly(command) {
+        let mut response = EditInteractionResponse::new()
+            .content(reply.content)
+            .embeds(reply.embeds);
+        for attachment in reply.attachments {
+            response = response.new_attachment(attachment);
+        }
+        outbound
+            .send(|http| async move { command.edit_response(&http, response).await })
+            .await?;
+    } else {
+        let followup = CreateInteractionResponseFollowup::new()
+            .ephemeral(reply.ephemeral)
+            .content(reply.content)
+            .embeds(reply.embeds)
+            .add_files(reply.attachments);
+        outbound
+            .send(|http| async move { command.create_followup(&http, followup).await })
+            .await?;
+        outbound
+            .send(|http| async move { command.delete_response(&http).await })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// The locale for messages posted to a guild's channels rather than to the user.
+fn guild_locale(command: &CommandInteraction) -> &str {
+    command.guild_locale.as_deref().unwrap_or(&command.locale)
+}
+
+fn get_string_option<'a>(options: &'a [ResolvedOption<'a>], name: &str) -> Result<&'a str> {
+    options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::String(value) if option.name == name => Some(value),
+            _ => None,
+        })
+        .with_context(|| format!("missing option: {}", name))
+}
+
+fn get_integer_option(options: &[ResolvedOption<'_>], name: &str) -> Result<i64> {
+    options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::Integer(value) if option.name == name => Some(value),
+            _ => None,
+        })
+        .with_context(|| format!("missing option: {}", name))
+}
+
+async fn gen_list(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    options: &[ResolvedOption<'_>],
+) -> Result<Reply> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| UserError(tr!(&command.locale, "error.guild-only")))?;
+    let server = get_string_option(options, "server")?;
+    let map: ArkMap = get_string_option(options, "map")?.parse()?;
+
+    let mut list = GeneratorList {
+        // Interaction IDs are unique snowflakes, so they double as list IDs.
+        list_id: command.id.get().to_be_bytes().to_vec(),
+        guild_id: guild_id.get(),
+        channel_id: command.channel_id.get(),
+        message_id: 0,
+        server: server.to_string(),
+        map,
+        locale: guild_locale(command).to_string(),
+    };
+
+    let generators = list.load_generators(&state.database.start_trx()?).await?;
+    let embed = list.render(&generators, &DateTime::now());
+
+    // Posted before the list is stored, so a stored list always has a message.
+    let channel_id = command.channel_id;
+    let message = state
+        .outbound
+        .send(|http| async move {
+            channel_id
+                .send_message(&http, CreateMessage::new().embed(embed))
+                .await
+        })
+        .await?;
+    list.message_id = message.id.get();
+    let update_job = Job::new(
+        JobAction::UpdateTimers(list.list_id.clone()),
+        DateTime::now().saturating_add(state.list_refresh_interval),
+    );
+    let stored = state
+        .database
+        .transact(async |trx| {
+            list.put(trx)?;
+            queue::enqueue(trx, &update_job)
+        })
+        .await;
+    if let Err(err) = stored {
+        // Nothing would ever update the message, so it's taken down again.
+        let message_id = message.id;
+        let deleted = state
+            .outbound
+            .send(|http| async move { channel_id.delete_message(&http, message_id).await })
+            .await;
+        if let Err(delete_err) = deleted {
+            warn!("failed to delete unstored list message: {:#}", delete_err);
+        }
+        return Err(err);
+    }
+
+    Ok(Reply::ephemeral(tr!(
+        &command.locale,
+        "list.tracking",
+        server = list.server,
+        map = locale::map_name(&command.locale, map)
+    )))
+}
+
+async fn gen_map(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    options: &[ResolvedOption<'_>],
+) -> Result<Reply> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| UserError(tr!(&command.locale, "error.guild-only")))?;
+    let server = get_string_option(options, "server")?;
+    let map: ArkMap = get_string_option(options, "map")?.parse()?;
+
+    let trx = state.database.start_trx()?;
+    let generators = TekGenerator::get_on_map(&trx, guild_id.get(), server, map).await?;
+    drop(trx);
+
+    let now = DateTime::now();
+    let png = render::map_image::render(map, &generators, &now)?;
+    let title = tr!(
+        &command.locale,
+        "render.location",
+        server = server,
+        map = locale::map_name(&command.locale, map)
+    );
+    let embed = render::generators_embed(&command.locale, title, &generators, &now)
+        .image(format!("attachment://{}", render::map_image::FILE_NAME));
+
+    Ok(Reply::public("")
+        .with_embed(embed)
+        .with_attachment(CreateAttachment::bytes(png, render::map_image::FILE_NAME)))
+}
+
+async fn gen_alerts_set(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    options: &[ResolvedOption<'_>],
+) -> Result<Reply> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| UserError(tr!(&command.locale, "error.guild-only")))?;
+    let channel_id = options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::Channel(channel) if option.name == "channel" => Some(channel.id),
+            _ => None,
+        })
+        .context("missing option: channel")?;
+    let role_id = options.iter().find_map(|option| match option.value {
+        ResolvedValue::Role(role) if option.name == "role" => Some(role.id),
+        _ => None,
+    });
+    let thresholds = match get_string_option(options, "thresholds") {
+        Ok(thresholds) => alerts::parse_thresholds(thresholds).map_err(|err| {
+            UserError(tr!(
+                &command.locale,
+                "alerts.invalid-thresholds",
+                error = err
+            ))
+        })?,
+        Err(_) => alerts::parse_thresholds(alerts::DEFAULT_THRESHOLDS)?,
+    };
+
+    // Replacing the rules retires any existing checker, see `AlertRules::check_job_id`.
+    let check_job = Job::new(JobAction::CheckAlerts(guild_id.get()), DateTime::now());
+    let rules = AlertRules {
+        guild_id: guild_id.get(),
+        channel_id: channel_id.get(),
+        role_id: role_id.map(|role_id| role_id.get()),
+        thresholds,
+        check_job_id: check_job.id(),
+        locale: guild_locale(command).to_string(),
+    };
+
+    state
+        .database
+        .transact(async |trx| {
+            rules.put(trx)?;
+            queue::enqueue(trx, &check_job)
+        })
+        .await?;
+
+    let thresholds = rules
+        .thresholds
+        .iter()
+        .map(|threshold| alerts::format_threshold(&command.locale, *threshold))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(Reply::ephemeral(tr!(
+        &command.locale,
+        "alerts.enabled",
+        channel = rules.channel_id,
+        thresholds = thresholds
+    )))
+}
+
+async fn gen_alerts_disable(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+) -> Result<Reply> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| UserError(tr!(&command.locale, "error.guild-only")))?;
+
+    // The checker job finishes on its own once it finds no rules.
+    state
+        .database
+        .transact(async |trx| {
+            AlertRules::delete(trx, &guild_id.get());
+            Ok(())
+        })
+        .await?;
+
+    Ok(Reply::ephemeral(tr!(&command.locale, "alerts.disabled")))
+}
+
+/// Starts a new reminder checker for the user, retiring any previous one.
+fn restart_reminders(trx: &mut Transaction, settings: &mut UserSettings) -> Result<()> {
+    let check_job = Job::new(JobAction::CheckReminders(settings.user_id), DateTime::now());
+    settings.check_job_id = check_job.id();
+    queue::enqueue(trx, &check_job)
+}
+
+/// Loads the settings of the user running `command`, remembering their locale for reminders.
+async fn load_user_settings(
+    trx: &Transaction,
+    command: &CommandInteraction,
+) -> Result<UserSettings> {
+    let user_id = command.user.id.get();
+    let mut settings = match UserSettings::get(trx, &user_id).await? {
+        Some(settings) => settings,
+        None => UserSettings::new(user_id)?,
+    };
+    settings.locale.clone_from(&command.locale);
+
+    Ok(settings)
+}
+
+async fn gen_subscribe(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    options: &[ResolvedOption<'_>],
+    subscribe: bool,
+) -> Result<Reply> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| UserError(tr!(&command.locale, "error.guild-only")))?;
+    let user_id = command.user.id.get();
+    let server = get_string_option(options, "server")?;
+    let map: ArkMap = get_string_option(options, "map")?.parse()?;
+    let map_name = locale::map_name(&command.locale, map);
+
+    let trx = state.database.start_trx()?;
+    let (target, description, embed) = match get_string_option(options, "generator") {
+        Ok(name) => {
+            let prefix = GeneratorKey::server_prefix(guild_id.get(), server);
+            let generators = TekGenerator::get_all(&trx, &prefix).await?;
+            let found = generators.iter().find(|generator| {
+                generator.map() == map && generator.name().eq_ignore_ascii_case(name)
+            });
+
+            match found {
+                Some(generator) => {
+                    let target = SubscriptionTarget::Generator {
+                        server: server.to_string(),
+                        id: generator.id(),
+                    };
+                    let embed =
+                        render::generator_embed(&command.locale, generator, &DateTime::now());
+                    (target, format!("**{}**", generator.name()), Some(embed))
+                }
+                None if !subscribe => {
+                    // A removed generator has no name left to look up, so
+                    // its subscription is matched by the ID it stored.
+                    let removed =
+                        removed_generator_subscriptions(&trx, command, server, &generators).await?;
+                    let Some(id) = name.parse().ok().filter(|id| removed.contains(id)) else {
+                        let mut content = tr!(
+                            &command.locale,
+                            "subscribe.unknown-generator",
+                            name = name,
+                            server = server,
+                            map = map_name
+                        );
+                        if !removed.is_empty() {
+                            let ids: Vec<String> = removed.iter().map(u64::to_string).collect();
+                            content.push('\n');
+                            content.push_str(&tr!(
+                                &command.locale,
+                                "subscribe.removed-generators",
+                                server = server,
+                                ids = ids.join(", ")
+                            ));
+                        }
+                        return Ok(Reply::ephemeral(content));
+                    };
+
+                    let target = SubscriptionTarget::Generator {
+                        server: server.to_string(),
+                        id,
+                    };
+                    let description = tr!(&command.locale, "subscribe.removed-generator", id = id);
+                    (target, description, None)
+                }
+                None => {
+                    return Ok(Reply::ephemeral(tr!(
+                        &command.locale,
+                        "subscribe.unknown-generator",
+                        name = name,
+                        server = server,
+                        map = map_name
+                    )));
+                }
+            }
+        }
+        Err(_) => {
+            let target = SubscriptionTarget::Map {
+                server: server.to_string(),
+                map,
+            };
+            let description = tr!(
+                &command.locale,
+                "subscribe.every-generator",
+                server = server,
+                map = map_name
+            );
+            (target, description, None)
+        }
+    };
+
+    let subscription = Subscription {
+        user_id,
+        guild_id: guild_id.get(),
+        target,
+    };
+
+    drop(trx);
+
+    let content = if subscribe {
+        let settings = state
+            .database
+            .transact(async |trx| {
+                let mut settings = load_user_settings(trx, command).await?;
+                subscription.put(trx)?;
+                restart_reminders(trx, &mut settings)?;
+                settings.put(trx)?;
+
+                Ok(settings)
+            })
+            .await?;
+
+        if settings.opted_out {
+            tr!(
+                &command.locale,
+                "subscribe.subscribed-opted-out",
+                target = description
+            )
+        } else {
+            tr!(
+                &command.locale,
+                "subscribe.subscribed",
+                target = description
+            )
+        }
+    } else {
+        let key: SubscriptionKey = subscription.key();
+        state
+            .database
+            .transact(async |trx| {
+                Subscription::delete(trx, &key);
+                Ok(())
+            })
+            .await?;
+        tr!(
+            &command.locale,
+            "subscribe.unsubscribed",
+            target = description
+        )
+    };
+
+    let mut reply = Reply::ephemeral(content);
+    if let Some(embed) = embed {
+        reply = reply.with_embed(embed);
+    }
+
+    Ok(reply)
+}
+
+/// The IDs of generators on `server` the user running `command` is still
+/// subscribed to, but which are no longer among `generators`.
+async fn removed_generator_subscriptions(
+    trx: &Transaction,
+    command: &CommandInteraction,
+    server: &str,
+    generators: &[TekGenerator],
+) -> Result<Vec<u64>> {
+    let guild_id = command.guild_id.map(|guild_id| guild_id.get());
+    let subscriptions = Subscription::get_all(trx, &command.user.id.get().as_key()).await?;
+
+    Ok(subscriptions
+        .into_iter()
+        .filter(|subscription| Some(subscription.guild_id) == guild_id)
+        .filter_map(|subscription| match subscription.target {
+            SubscriptionTarget::Generator {
+                server: subscribed_server,
+                id,
+            } if subscribed_server == server => Some(id),
+            _ => None,
+        })
+        .filter(|id| !generators.iter().any(|generator| generator.id() == *id))
+        .collect())
+}
+
+async fn gen_reminders_thresholds(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    options: &[ResolvedOption<'_>],
+) -> Result<Reply> {
+    let thresholds = get_string_option(options, "thresholds")?;
+    let thresholds = alerts::parse_thresholds(thresholds).map_err(|err| {
+        UserError(tr!(
+            &command.locale,
+            "alerts.invalid-thresholds",
+            error = err
+        ))
+    })?;
+
+    let settings = state
+        .database
+        .transact(async |trx| {
+            let mut settings = load_user_settings(trx, command).await?;
+            settings.thresholds = thresholds.clone();
+            settings.put(trx)?;
+
+            Ok(settings)
+        })
+        .await?;
+
+    let thresholds = settings
+        .thresholds
+        .iter()
+        .map(|threshold| alerts::format_threshold(&command.locale, *threshold))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(Reply::ephemeral(tr!(
+        &command.locale,
+        "reminders.thresholds",
+        thresholds = thresholds
+    )))
+}
+
+async fn gen_reminders_quiet_hours(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    options: &[ResolvedOption<'_>],
+) -> Result<Reply> {
+    let start = get_integer_option(options, "start")?;
+    let end = get_integer_option(options, "end")?;
+    let utc_offset = get_integer_option(options, "utc-offset").unwrap_or(0);
+
+    let settings = state
+        .database
+        .transact(async |trx| {
+            let mut settings = load_user_settings(trx, command).await?;
+            settings.quiet_hours = (start != end).then(|| QuietHours {
+                start: (start * 60) as u16,
+                end: (end * 60) as u16,
+                utc_offset_minutes: (utc_offset * 60) as i16,
+            });
+            settings.put(trx)?;
+
+            Ok(settings)
+        })
+        .await?;
+
+    let content = match settings.quiet_hours {
+        Some(_) => tr!(
+            &command.locale,
+            "reminders.quiet-hours",
+            start = format!("{:02}", start),
+            end = format!("{:02}", end),
+            offset = format!("{:+}", utc_offset)
+        ),
+        None => tr!(&command.locale, "reminders.quiet-hours-off"),
+    };
+
+    Ok(Reply::ephemeral(content))
+}
+
+async fn gen_reminders_toggle(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    enabled: bool,
+) -> Result<Reply> {
+    state
+        .database
+        .transact(async |trx| {
+            let mut settings = load_user_settings(trx, command).await?;
+            settings.opted_out = !enabled;
+            if enabled {
+                restart_reminders(trx, &mut settings)?;
+            }
+            settings.put(trx)
+        })
+        .await?;
+
+    let content = if enabled {
+        tr!(&command.locale, "reminders.on")
+    } else {
+        tr!(&command.locale, "reminders.off")
+    };
+
+    Ok(Reply::ephemeral(content))
+}
+
+fn format_roles(roles: &[u64]) -> String {
+    roles
+        .iter()
+        .map(|role_id| format!("<@&{}>", role_id))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+async fn gen_permissions_update(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    options: &[ResolvedOption<'_>],
+    grant: bool,
+) -> Result<Reply> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| UserError(tr!(&command.locale, "error.guild-only")))?;
+    let level: AccessLevel = get_string_option(options, "level")?.parse()?;
+    let role_id = options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::Role(role) if option.name == "role" => Some(role.id.get()),
+            _ => None,
+        })
+        .context("missing option: role")?;
+
+    state
+        .database
+        .transact(async |trx| {
+            let mut guild_permissions = GuildPermissions::get(trx, &guild_id.get())
+                .await?
+                .unwrap_or_else(|| GuildPermissions::new(guild_id.get()));
+            let roles = guild_permissions.roles_mut(level);
+            if grant {
+                if !roles.contains(&role_id) {
+                    roles.push(role_id);
+                }
+            } else {
+                roles.retain(|role| *role != role_id);
+            }
+            guild_permissions.put(trx)
+        })
+        .await?;
+
+    let level = locale::access_level_name(&command.locale, level);
+    let content = if grant {
+        tr!(
+            &command.locale,
+            "permissions.granted",
+            role = role_id,
+            level = level
+        )
+    } else {
+        tr!(
+            &command.locale,
+            "permissions.revoked",
+            role = role_id,
+            level = level
+        )
+    };
+
+    Ok(Reply::ephemeral(content))
+}
+
+async fn gen_permissions_show(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+) -> Result<Reply> {
+    let guild_id = command
+        .guild_id
+        .ok_or_else(|| UserError(tr!(&command.locale, "error.guild-only")))?;
+
+    let trx = state.database.start_trx()?;
+    let guild_permissions = GuildPermissions::get(&trx, &guild_id.get())
+        .await?
+        .unwrap_or_else(|| GuildPermissions::new(guild_id.get()));
+
+    let mut content = String::new();
+    for level in AccessLevel::ALL {
+        let roles = guild_permissions.roles(level);
+        let holders = match (roles.is_empty(), level) {
+            (true, AccessLevel::Admin) => tr!(&command.locale, "permissions.managers"),
+            (true, _) => tr!(&command.locale, "permissions.everyone"),
+            (false, _) => format_roles(roles),
+        };
+        let level = locale::access_level_name(&command.locale, level);
+        content.push_str(&format!("**{}**: {}\n", level, holders));
+    }
+
+    Ok(Reply::ephemeral(content))
+}
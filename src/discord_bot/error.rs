@@ -0,0 +1,72 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use std::fmt::{Display, Formatter};
+use tracing::debug;
+
+/// Why an interaction request was rejected before reaching a handler.
+#[derive(Debug)]
+pub(super) enum InteractionError {
+    MissingHeader(&'static str),
+    InvalidSignature,
+    /// The signed timestamp is not a number of seconds.
+    InvalidTimestamp,
+    StaleTimestamp,
+    Replayed,
+    /// The signature checks out, but the body is not an interaction we understand.
+    MalformedBody(String),
+    UnsupportedType,
+}
+
+impl InteractionError {
+    fn status(&self) -> StatusCode {
+        match self {
+            InteractionError::MissingHeader(_)
+            | InteractionError::InvalidSignature
+            | InteractionError::InvalidTimestamp
+            | InteractionError::StaleTimestamp
+            | InteractionError::Replayed => StatusCode::UNAUTHORIZED,
+            InteractionError::MalformedBody(_) | InteractionError::UnsupportedType => {
+                StatusCode::BAD_REQUEST
+            }
+        }
+    }
+}
+
+impl Display for InteractionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InteractionError::MissingHeader(header) => write!(f, "missing or invalid {}", header),
+            InteractionError::InvalidSignature => write!(f, "invalid signature"),
+            InteractionError::InvalidTimestamp => write!(f, "invalid timestamp"),
+            InteractionError::StaleTimestamp => {
+                write!(f, "timestamp is outside the freshness window")
+            }
+            InteractionError::Replayed => write!(f, "interaction was already received"),
+            InteractionError::MalformedBody(err) => write!(f, "malformed body: {}", err),
+            InteractionError::UnsupportedType => write!(f, "unsupported interaction type"),
+        }
+    }
+}
+
+impl std::error::Error for InteractionError {}
+
+impl IntoResponse for InteractionError {
+    fn into_response(self) -> Response {
+        debug!("rejecting interaction: {}", self);
+
+        self.status().into_response()
+    }
+}
+
+/// A command failure caused by how the command was used, shown to the user as is.
+/// Any other error only tells the user that something went wrong.
+#[derive(Debug)]
+pub(super) struct UserError(pub(super) String);
+
+impl Display for UserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UserError {}
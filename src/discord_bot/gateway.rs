@@ -0,0 +1,72 @@
+use crate::discord_bot::{DiscordBotState, InteractionReply};
+use crate::Result;
+use serenity::all::{Context, EventHandler, GatewayIntents, Interaction, Ready};
+use serenity::async_trait;
+use serenity::Client;
+use std::sync::Arc;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace_span, warn, Instrument};
+
+struct GatewayHandler {
+    state: Arc<DiscordBotState>,
+}
+
+#[async_trait]
+impl EventHandler for GatewayHandler {
+    async fn interaction_create(&self, _ctx: Context, interaction: Interaction) {
+        let interaction_id = interaction.id();
+        let interaction_span = trace_span!("Interaction", "id" = interaction_id.get());
+        let token = interaction.token().to_string();
+
+        let Some(reply) = InteractionReply::route(interaction) else {
+            debug!(parent: &interaction_span, "ignoring unsupported interaction type");
+            return;
+        };
+
+        // Over the gateway the initial response is a separate request, which
+        // has to land before the deferred work edits it.
+        let response = reply.initial_response();
+        if let Err(err) = self
+            .state
+            .outbound
+            .send(|http| async move {
+                http.create_interaction_response(interaction_id, &token, &response, vec![])
+                    .await
+            })
+            .instrument(interaction_span.clone())
+            .await
+        {
+            warn!(parent: &interaction_span, "failed to respond to interaction: {}", err);
+            return;
+        }
+
+        let _entered = interaction_span.enter();
+        reply.spawn_deferred(&self.state);
+    }
+
+    async fn ready(&self, _ctx: Context, ready: Ready) {
+        info!("connected to the Discord gateway as {}", ready.user.name);
+    }
+}
+
+/// Receives interactions over the gateway until cancelled, for when Discord
+/// can't reach the interactions endpoint.
+pub(super) async fn run(
+    state: Arc<DiscordBotState>,
+    bot_token: &str,
+    cancellation_token: CancellationToken,
+) -> Result<()> {
+    // Interactions are delivered regardless of intents.
+    let mut client = Client::builder(bot_token, GatewayIntents::empty())
+        .event_handler(GatewayHandler { state })
+        .await?;
+    let shard_manager = client.shard_manager.clone();
+
+    select! {
+        result = client.start() => result?,
+        _ = cancellation_token.cancelled() => shard_manager.shutdown_all().await,
+    }
+
+    Ok(())
+}
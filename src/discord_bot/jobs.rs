@@ -1,22 +1,186 @@
+pub(crate) mod queue;
+
+use crate::database::{DbModel, Transaction};
+use crate::discord_bot::locale::{self, tr};
+use crate::discord_bot::{alerts, reminders, render, DiscordBotState};
 use crate::types::coordinates::ArkMap;
+use crate::types::tracking::TekGenerator;
+use crate::types::util::DateTime;
+use crate::Result;
 use rkyv::{Archive, Deserialize, Serialize};
-use serenity::all::MessageId;
-use std::future::Future;
+use serenity::all::{ChannelId, CreateEmbed, EditMessage, MessageId};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace_span, warn, Instrument};
+
+/// How often the queue is checked for due jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const CLAIM_BATCH_SIZE: usize = 16;
 
+/// A channel message listing every generator of a map in an ARK server,
+/// kept up to date by [`JobAction::UpdateTimers`].
 #[derive(Archive, Serialize, Deserialize)]
-struct GeneratorList {
-    server: String,
-    map: ArkMap,
-    list_id: Vec<u8>,
+pub(crate) struct GeneratorList {
+    pub(crate) list_id: Vec<u8>,
+    pub(crate) guild_id: u64,
+    pub(crate) channel_id: u64,
+    pub(crate) message_id: u64,
+    pub(crate) server: String,
+    pub(crate) map: ArkMap,
+    /// The guild's locale when the list was posted.
+    pub(crate) locale: String,
+}
+
+impl GeneratorList {
+    pub(crate) async fn load_generators(&self, trx: &Transaction) -> Result<Vec<TekGenerator>> {
+        TekGenerator::get_on_map(trx, self.guild_id, &self.server, self.map).await
+    }
+
+    pub(crate) fn render(&self, generators: &[TekGenerator], now: &DateTime) -> CreateEmbed {
+        let title = tr!(
+            &self.locale,
+            "render.location",
+            server = self.server,
+            map = locale::map_name(&self.locale, self.map)
+        );
+
+        render::generators_embed(&self.locale, title, generators, now)
+    }
 }
 
 #[derive(Archive, Serialize, Deserialize)]
 #[repr(u16)]
-enum JobAction {
-    UpdateTimers() = 0,
+pub(crate) enum JobAction {
+    /// Edits the message of the [`GeneratorList`] with the given `list_id`.
+    UpdateTimers(Vec<u8>) = 0,
+    /// Checks the generators of a guild against its [`AlertRules`](alerts::AlertRules).
+    CheckAlerts(u64) = 1,
+    /// Checks the subscriptions of a user and sends them direct messages.
+    CheckReminders(u64) = 2,
+}
+
+/// What a job asks of the queue after running successfully.
+pub(crate) enum JobOutcome {
+    Finished,
+    Reschedule(Duration),
 }
 
 #[derive(Archive, Serialize, Deserialize)]
-struct Job {
+pub(crate) struct Job {
+    id: u64,
+    run_at: DateTime,
+    attempts: u32,
+    last_error: Option<String>,
+    lease_expires_at: Option<DateTime>,
+    /// Set on every claim, so a worker whose lease expired can tell the job
+    /// has since been claimed by another.
+    claim_token: Option<u64>,
     action: JobAction,
 }
+
+impl Job {
+    pub(crate) fn new(action: JobAction, run_at: DateTime) -> Self {
+        Self {
+            id: rand::random(),
+            run_at,
+            attempts: 0,
+            last_error: None,
+            lease_expires_at: None,
+            claim_token: None,
+            action,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub(crate) fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    pub(crate) async fn run(&self, state: &DiscordBotState) -> Result<JobOutcome> {
+        match &self.action {
+            JobAction::UpdateTimers(list_id) => update_timers(state, list_id).await,
+            JobAction::CheckAlerts(guild_id) => {
+                alerts::check_alerts(state, self.id, *guild_id).await
+            }
+            JobAction::CheckReminders(user_id) => {
+                reminders::check_reminders(state, self.id, *user_id).await
+            }
+        }
+    }
+}
+
+async fn update_timers(state: &DiscordBotState, list_id: &Vec<u8>) -> Result<JobOutcome> {
+    let trx = state.database.start_trx()?;
+    let Some(list) = GeneratorList::get(&trx, list_id).await? else {
+        debug!("generator list no longer exists, not rescheduling");
+        return Ok(JobOutcome::Finished);
+    };
+    let generators = list.load_generators(&trx).await?;
+    let embed = list.render(&generators, &DateTime::now());
+
+    state
+        .outbound
+        .edit_message(
+            ChannelId::new(list.channel_id),
+            MessageId::new(list.message_id),
+            // Clears the text content of lists posted before they were embeds.
+            EditMessage::new().content("").embed(embed),
+        )
+        .await?;
+
+    Ok(JobOutcome::Reschedule(state.list_refresh_interval))
+}
+
+/// Claims and runs due jobs from the queue until cancelled.
+pub(crate) async fn run_worker(state: Arc<DiscordBotState>, cancellation_token: CancellationToken) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        select! {
+            _ = cancellation_token.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        let now = DateTime::now();
+        if let Err(err) = queue::requeue_expired(&state.database, &now).await {
+            debug!("failed to requeue expired jobs: {:#}", err);
+        }
+
+        let jobs = match queue::claim_due(&state.database, &now, CLAIM_BATCH_SIZE).await {
+            Ok(jobs) => jobs,
+            Err(err) => {
+                // Most likely another instance claimed the same jobs first.
+                debug!("failed to claim jobs: {:#}", err);
+                continue;
+            }
+        };
+
+        for job in jobs {
+            let job_span = trace_span!("Job", "id" = job.id);
+
+            async {
+                let result = job.run(&state).await;
+                let recorded = match result {
+                    Ok(outcome) => queue::complete(&state.database, job, outcome).await,
+                    Err(err) => queue::fail(&state.database, job, err).await,
+                };
+                if let Err(err) = recorded {
+                    warn!("failed to record job result: {:#}", err);
+                }
+            }
+            .instrument(job_span)
+            .await;
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Durable job queue stored in the database.
+//!
+//! Jobs move between three keyspaces:
+//!  - `jobs/queued/`, ordered by run-at time so due jobs are a single range read,
+//!  - `jobs/claimed/`, while a worker holds a lease on them,
+//!  - `jobs/dead/`, once they have failed for good and are kept for inspection.
+//!
+//! Claiming reads and moves jobs in one transaction. Two workers claiming the
+//! same jobs conflict, and the one committing second runs its claim again.
+
+use crate::database::{AsKey, Database, ToValue, Transaction};
+use crate::discord_bot::jobs::{Job, JobOutcome};
+use crate::types::util::DateTime;
+use crate::Result;
+use serenity::http::HttpError;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const QUEUED: &[u8] = b"jobs/queued/";
+const CLAIMED: &[u8] = b"jobs/claimed/";
+const DEAD: &[u8] = b"jobs/dead/";
+
+/// How long a claimed job may run before other workers consider it abandoned.
+const LEASE_DURATION: Duration = Duration::from_secs(5 * 60);
+const MAX_ATTEMPTS: u32 = 8;
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(15 * 60);
+
+fn queued_key(job: &Job) -> Vec<u8> {
+    [QUEUED, &job.run_at.as_key(), &job.id.as_key()].concat()
+}
+
+fn claimed_key(id: u64) -> Vec<u8> {
+    [CLAIMED, &id.as_key()].concat()
+}
+
+fn dead_key(id: u64) -> Vec<u8> {
+    [DEAD, &id.as_key()].concat()
+}
+
+/// Adds `job` to the queue once `trx` commits.
+pub(crate) fn enqueue(trx: &mut Transaction, job: &Job) -> Result<()> {
+    trx.set(&queued_key(job), &job.to_value()?);
+
+    Ok(())
+}
+
+/// Claims up to `limit` jobs whose run-at time is at or before `now`.
+pub(crate) async fn claim_due(db: &Database, now: &DateTime, limit: usize) -> Result<Vec<Job>> {
+    // Exclusive end, so jobs due exactly now are included.
+    let end = [
+        QUEUED,
+        &now.saturating_add(Duration::from_millis(1)).as_key(),
+    ]
+    .concat();
+
+    db.transact(async |trx| {
+        let due = trx.get_range(QUEUED, &end, Some(limit)).await?;
+
+        let mut claimed = vec![];
+        for (key, bytes) in due {
+            let mut job = Job::from_value(&bytes)?;
+            job.lease_expires_at = Some(now.saturating_add(LEASE_DURATION));
+            job.claim_token = Some(rand::random());
+
+            trx.clear(&key);
+            trx.set(&claimed_key(job.id), &job.to_value()?);
+            claimed.push(job);
+        }
+
+        Ok(claimed)
+    })
+    .await
+}
+
+/// Returns jobs whose lease has expired to the queue, e.g. after a worker crashed.
+pub(crate) async fn requeue_expired(db: &Database, now: &DateTime) -> Result<()> {
+    let requeued = db
+        .transact(async |trx| {
+            let mut requeued = 0;
+
+            for (key, bytes) in trx.get_prefix(CLAIMED).await? {
+                let mut job = Job::from_value(&bytes)?;
+                if job
+                    .lease_expires_at
+                    .is_some_and(|expires_at| expires_at > *now)
+                {
+                    continue;
+                }
+
+                job.lease_expires_at = None;
+                job.claim_token = None;
+                job.run_at = *now;
+                trx.clear(&key);
+                enqueue(trx, &job)?;
+                requeued += 1;
+            }
+
+            Ok(requeued)
+        })
+        .await?;
+
+    if requeued > 0 {
+        debug!("requeued {} jobs with expired leases", requeued);
+    }
+
+    Ok(())
+}
+
+/// Releases a successfully run job, rescheduling it if asked to.
+pub(crate) async fn complete(db: &Database, mut job: Job, outcome: JobOutcome) -> Result<()> {
+    let claim_token = job.claim_token.take();
+    let reschedule = match outcome {
+        JobOutcome::Finished => false,
+        JobOutcome::Reschedule(after) => {
+            job.run_at = DateTime::now().saturating_add(after);
+            job.attempts = 0;
+            job.last_error = None;
+            job.lease_expires_at = None;
+            true
+        }
+    };
+
+    db.transact(async |trx| {
+        if release_claim(trx, job.id, claim_token).await? && reschedule {
+            enqueue(trx, &job)?;
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+/// Releases a failed job, either retrying it with backoff or dead-lettering it.
+pub(crate) async fn fail(db: &Database, mut job: Job, err: anyhow::Error) -> Result<()> {
+    let claim_token = job.claim_token.take();
+    job.attempts += 1;
+    job.last_error = Some(format!("{:#}", err));
+    job.lease_expires_at = None;
+
+    let retry = is_retryable(&err) && job.attempts < MAX_ATTEMPTS;
+    let backoff = backoff(job.attempts);
+    if retry {
+        job.run_at = DateTime::now().saturating_add(backoff);
+    }
+
+    let released = db
+        .transact(async |trx| {
+            if !release_claim(trx, job.id, claim_token).await? {
+                return Ok(false);
+            }
+
+            if retry {
+                enqueue(trx, &job)?;
+            } else {
+                trx.set(&dead_key(job.id), &job.to_value()?);
+            }
+
+            Ok(true)
+        })
+        .await?;
+
+    if !released {
+        return Ok(());
+    }
+    if retry {
+        debug!("job failed, retrying in {:?}: {:#}", backoff, err);
+    } else {
+        warn!(
+            "job failed after {} attempts, moving to dead letters: {:#}",
+            job.attempts, err
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes the claim on a job. Returns `false` if the lease was lost in the
+/// meantime, in which case the job has been requeued or claimed by another
+/// worker and must be left alone.
+async fn release_claim(trx: &mut Transaction, id: u64, claim_token: Option<u64>) -> Result<bool> {
+    let key = claimed_key(id);
+    let still_held = match trx.get(&key).await? {
+        Some(bytes) => Job::from_value(&bytes)?.claim_token == claim_token,
+        None => false,
+    };
+    if !still_held {
+        warn!("lease on job {} expired before it finished", id);
+        return Ok(false);
+    }
+    trx.clear(&key);
+
+    Ok(true)
+}
+
+pub(crate) async fn dead_letters(db: &Database) -> Result<Vec<Job>> {
+    let trx = db.start_trx()?;
+    let mut jobs = vec![];
+
+    for (_, bytes) in trx.get_prefix(DEAD).await? {
+        jobs.push(Job::from_value(&bytes)?);
+    }
+
+    Ok(jobs)
+}
+
+/// Moves a dead-lettered job back to the queue to run immediately.
+/// Returns `false` if no such job exists.
+pub(crate) async fn retry_dead_letter(db: &Database, id: u64) -> Result<bool> {
+    let key = dead_key(id);
+
+    db.transact(async |trx| {
+        let Some(bytes) = trx.get(&key).await? else {
+            return Ok(false);
+        };
+
+        let mut job = Job::from_value(&bytes)?;
+        job.attempts = 0;
+        job.claim_token = None;
+        job.run_at = DateTime::now();
+        trx.clear(&key);
+        enqueue(trx, &job)?;
+
+        Ok(true)
+    })
+    .await
+}
+
+/// Only transient Discord API failures are worth retrying. Anything else,
+/// such as missing permissions or a deleted message, will fail the same way
+/// again.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    // Coalesced message edits share the error of the edit that was sent.
+    let serenity_err = err
+        .downcast_ref::<serenity::Error>()
+        .or_else(|| err.downcast_ref::<Arc<serenity::Error>>().map(Arc::as_ref));
+
+    match serenity_err {
+        Some(serenity::Error::Http(HttpError::UnsuccessfulRequest(response))) => {
+            is_retryable_status(response.status_code.as_u16())
+        }
+        // The request never got a response, e.g. a timeout or reset connection.
+        Some(serenity::Error::Http(HttpError::Request(_))) => true,
+        _ => false,
+    }
+}
+
+/// Server errors and rate limits.
+fn is_retryable_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+fn backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+
+    BACKOFF_BASE.saturating_mul(factor).min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::jobs::queue::{
+        backoff, is_retryable, is_retryable_status, BACKOFF_MAX,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn exponential_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(2), Duration::from_secs(10));
+        assert_eq!(backoff(4), Duration::from_secs(40));
+        assert_eq!(backoff(30), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn retries_only_transient_failures() {
+        assert!(is_retryable_status(429));
+        assert!(is_retryable_status(502));
+        assert!(!is_retryable_status(403));
+        assert!(!is_retryable_status(404));
+        assert!(!is_retryable(&anyhow::anyhow!("generator list is corrupt")));
+    }
+
+    #[cfg(feature = "rdb")]
+    #[tokio::test]
+    async fn expired_worker_leaves_new_claim_alone() {
+        use crate::database::Database;
+        use crate::discord_bot::jobs::queue::{
+            claim_due, complete, dead_letters, enqueue, fail, requeue_expired, CLAIMED,
+            LEASE_DURATION,
+        };
+        use crate::discord_bot::jobs::{Job, JobAction, JobOutcome};
+        use crate::types::util::DateTime;
+
+        let database_dir =
+            std::env::temp_dir().join(format!("genny-jobs-{:016x}", rand::random::<u64>()));
+        let database = Database::open_rocksdb(&database_dir).unwrap();
+
+        let now = DateTime::now();
+        let mut trx = database.start_trx().unwrap();
+        enqueue(&mut trx, &Job::new(JobAction::CheckAlerts(1), now)).unwrap();
+        trx.commit().await.unwrap();
+
+        // Worker A claims the job and stalls past its lease, so the job is
+        // requeued and claimed by worker B.
+        let mut worker_a = claim_due(&database, &now, 1).await.unwrap();
+        let later = now.saturating_add(LEASE_DURATION + Duration::from_secs(1));
+        requeue_expired(&database, &later).await.unwrap();
+        let mut worker_b = claim_due(&database, &later, 1).await.unwrap();
+        assert_eq!(worker_b.len(), 1);
+
+        // A finishing late must neither release nor dead-letter B's claim.
+        let job_a = worker_a.pop().unwrap();
+        fail(&database, job_a, anyhow::anyhow!("stalled"))
+            .await
+            .unwrap();
+        let trx = database.start_trx().unwrap();
+        assert_eq!(trx.get_prefix(CLAIMED).await.unwrap().len(), 1);
+        drop(trx);
+        assert!(dead_letters(&database).await.unwrap().is_empty());
+
+        let job_b = worker_b.pop().unwrap();
+        complete(&database, job_b, JobOutcome::Finished)
+            .await
+            .unwrap();
+        let trx = database.start_trx().unwrap();
+        assert!(trx.get_prefix(CLAIMED).await.unwrap().is_empty());
+        drop(trx);
+
+        let _ = std::fs::remove_dir_all(&database_dir);
+    }
+}
@@ -0,0 +1,174 @@
+//! Translations of bot messages and command descriptions.
+//!
+//! Each locale has a file in `resources/locales/` named after its Discord
+//! locale code, with one `key = value` pair per line. Values may refer to
+//! arguments as `{name}`. Keys missing from a locale fall back to
+//! [`DEFAULT_LOCALE`], so a translation can be added a few keys at a time.
+
+use crate::discord_bot::permissions::AccessLevel;
+use crate::types::coordinates::ArkMap;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::LazyLock;
+use tracing::warn;
+
+pub(crate) const DEFAULT_LOCALE: &str = "en-US";
+
+const TRANSLATION_FILES: [(&str, &str); 3] = [
+    (
+        DEFAULT_LOCALE,
+        include_str!("../../resources/locales/en-US.lang"),
+    ),
+    ("de", include_str!("../../resources/locales/de.lang")),
+    ("es-ES", include_str!("../../resources/locales/es-ES.lang")),
+];
+
+type Translations = HashMap<&'static str, &'static str>;
+
+static TRANSLATIONS: LazyLock<HashMap<&'static str, Translations>> = LazyLock::new(|| {
+    TRANSLATION_FILES
+        .into_iter()
+        .map(|(locale, source)| (locale, parse(source)))
+        .collect()
+});
+
+fn parse(source: &'static str) -> Translations {
+    source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect()
+}
+
+/// Finds the translations closest to `locale`, e.g. `de` for `de-AT`.
+fn translations_for(locale: &str) -> Option<&'static Translations> {
+    TRANSLATIONS.get(locale).or_else(|| {
+        let (language, _) = locale.split_once('-')?;
+        TRANSLATIONS.get(language)
+    })
+}
+
+fn lookup(locale: &str, key: &str) -> Option<&'static str> {
+    translations_for(locale)
+        .and_then(|translations| translations.get(key))
+        .or_else(|| TRANSLATIONS[DEFAULT_LOCALE].get(key))
+        .copied()
+}
+
+/// Fills in `args` in a single pass over `template`, so placeholders within
+/// the values, such as in user-chosen names, are left as they are.
+/// Placeholders without an argument are kept too.
+fn substitute(template: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let Some(end) = placeholder.find('}') else {
+            rest = placeholder;
+            break;
+        };
+        let name = &placeholder[1..end];
+        match args.iter().find(|(arg_name, _)| *arg_name == name) {
+            Some((_, value)) => text.push_str(&value.to_string()),
+            None => text.push_str(&placeholder[..=end]),
+        }
+        rest = &placeholder[end + 1..];
+    }
+    text.push_str(rest);
+
+    text
+}
+
+/// Looks up `key` for `locale` and fills in `args`. Prefer the [`tr!`] macro.
+pub(crate) fn translate(locale: &str, key: &str, args: &[(&str, &dyn Display)]) -> String {
+    match lookup(locale, key) {
+        Some(template) => substitute(template, args),
+        None => {
+            warn!("missing translation: {}", key);
+            key.to_string()
+        }
+    }
+}
+
+/// The translations of `key` in every locale other than the default one,
+/// as needed for Discord's command localizations.
+pub(crate) fn localizations(
+    key: &str,
+    args: &[(&str, &dyn Display)],
+) -> Vec<(&'static str, String)> {
+    TRANSLATIONS
+        .iter()
+        .filter(|(locale, _)| **locale != DEFAULT_LOCALE)
+        .filter_map(|(locale, translations)| {
+            let template = translations.get(key)?;
+            Some((*locale, substitute(template, args)))
+        })
+        .collect()
+}
+
+/// Translates a message key, e.g. `tr!(locale, "list.tracking", server = name, map = map)`.
+macro_rules! tr {
+    ($locale:expr, $key:expr $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::discord_bot::locale::translate(
+            $locale,
+            $key,
+            &[$((stringify!($name), &$value as &dyn std::fmt::Display)),*],
+        )
+    };
+}
+pub(crate) use tr;
+
+pub(crate) fn map_name(locale: &str, map: ArkMap) -> String {
+    translate(locale, &format!("map.{}", map.choice_value()), &[])
+}
+
+pub(crate) fn access_level_name(locale: &str, level: AccessLevel) -> String {
+    translate(locale, &format!("access.{}", level.choice_value()), &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::locale::{substitute, tr, DEFAULT_LOCALE, TRANSLATIONS};
+    use std::fmt::Display;
+
+    #[test]
+    fn every_locale_has_known_keys() {
+        let default = &TRANSLATIONS[DEFAULT_LOCALE];
+
+        for (locale, translations) in TRANSLATIONS.iter() {
+            for key in translations.keys() {
+                // Name localizations have no default, the command name is used instead.
+                assert!(
+                    default.contains_key(key) || key.ends_with(".name"),
+                    "{} has unknown key {}",
+                    locale,
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_language_and_default() {
+        assert_eq!(tr!("de-AT", "access.viewer"), "Betrachter");
+        assert_eq!(
+            tr!("ja", "list.tracking", server = "PvE", map = "X"),
+            "Now tracking generators on PvE — X."
+        );
+    }
+
+    #[test]
+    fn values_are_not_substituted_again() {
+        assert_eq!(
+            tr!("en-US", "list.tracking", server = "{map}", map = "X"),
+            "Now tracking generators on {map} — X."
+        );
+        assert_eq!(
+            substitute("{a} {missing} {b", &[("a", &"{b}" as &dyn Display)]),
+            "{b} {missing} {b"
+        );
+    }
+}
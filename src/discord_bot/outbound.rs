@@ -0,0 +1,410 @@
+//! The single way out to the Discord API.
+//!
+//! Every request first takes a token from a bucket shared by the whole bot,
+//! so bursts such as many generator lists refreshing at once are spread out
+//! below Discord's global limit instead of being answered with 429s. Per-route
+//! buckets are tracked by serenity's ratelimiter behind it, which waits out
+//! `X-RateLimit-*` headers and reports every rate limit it hits to the metrics.
+//!
+//! Message edits are coalesced: while an edit to a message is in flight,
+//! newer edits to it only replace the one waiting, so a slow route never
+//! builds up a backlog of outdated content.
+
+use crate::Result;
+use anyhow::anyhow;
+use serenity::all::{ApplicationId, ChannelId, EditMessage, MessageId};
+use serenity::http::ratelimiting::RatelimitInfo;
+use serenity::http::{Http, HttpBuilder};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::debug;
+
+type EditOutcome = std::result::Result<(), Arc<serenity::Error>>;
+
+/// Requests per second allowed by Discord for a bot across all routes.
+pub(crate) const GLOBAL_RATE_LIMIT: u32 = 50;
+
+#[derive(Default)]
+pub(crate) struct OutboundMetrics {
+    requests: AtomicU64,
+    failures: AtomicU64,
+    in_flight: AtomicU64,
+    coalesced_edits: AtomicU64,
+    /// Requests that had to wait for the global bucket.
+    throttled: AtomicU64,
+    throttled_millis: AtomicU64,
+    /// Rate limits reported by Discord, which serenity waited out.
+    rate_limited: AtomicU64,
+    rate_limited_global: AtomicU64,
+}
+
+impl OutboundMetrics {
+    fn record_rate_limit(&self, info: &RatelimitInfo) {
+        debug!("rate limited on {} for {:?}", info.path, info.timeout);
+        let counter = if info.global {
+            &self.rate_limited_global
+        } else {
+            &self.rate_limited
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let metrics = [
+            ("requests_total", "counter", &self.requests),
+            ("failures_total", "counter", &self.failures),
+            ("in_flight", "gauge", &self.in_flight),
+            ("coalesced_edits_total", "counter", &self.coalesced_edits),
+            ("throttled_total", "counter", &self.throttled),
+            (
+                "throttled_milliseconds_total",
+                "counter",
+                &self.throttled_millis,
+            ),
+            ("rate_limited_total", "counter", &self.rate_limited),
+            (
+                "rate_limited_global_total",
+                "counter",
+                &self.rate_limited_global,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (name, kind, value) in metrics {
+            let _ = writeln!(text, "# TYPE discord_outbound_{} {}", name, kind);
+            let _ = writeln!(
+                text,
+                "discord_outbound_{} {}",
+                name,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        text
+    }
+}
+
+/// A token bucket holding up to one second worth of requests.
+struct GlobalBucket {
+    rate: f64,
+    /// Tokens left and when they were last topped up.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl GlobalBucket {
+    fn new(requests_per_second: u32) -> Self {
+        let rate = requests_per_second.max(1) as f64;
+
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Waits until a request may be sent, returning how long that took.
+    async fn acquire(&self) -> Duration {
+        let mut waited = Duration::ZERO;
+
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+                let (tokens, topped_up_at) = &mut *state;
+                let now = Instant::now();
+                let refill = now.duration_since(*topped_up_at).as_secs_f64() * self.rate;
+                *tokens = (*tokens + refill).min(self.rate);
+                *topped_up_at = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return waited;
+                }
+                Duration::from_secs_f64((1.0 - *tokens) / self.rate)
+            };
+
+            tokio::time::sleep(wait).await;
+            waited += wait;
+        }
+    }
+}
+
+/// Tracks which keys have an edit in flight and the newest edit waiting behind it.
+struct EditSlots<K, T, R> {
+    slots: Mutex<HashMap<K, Option<Waiting<T, R>>>>,
+}
+
+/// The newest edit waiting for a key, and everyone whose edit it replaced
+/// or carries, to be told how sending it went.
+struct Waiting<T, R> {
+    edit: T,
+    waiters: Vec<oneshot::Sender<R>>,
+}
+
+impl<K: Eq + Hash + Clone, T, R: Clone> EditSlots<K, T, R> {
+    fn new() -> Self {
+        Self {
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the edit and its slot if the caller should send it now, or
+    /// the outcome of the send that will carry it otherwise.
+    fn begin(
+        &self,
+        key: K,
+        edit: T,
+    ) -> std::result::Result<(T, EditInFlight<'_, K, T, R>), oneshot::Receiver<R>> {
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match slots.get_mut(&key) {
+            Some(waiting) => {
+                let (sender, receiver) = oneshot::channel();
+                let mut waiters = waiting
+                    .take()
+                    .map(|waiting| waiting.waiters)
+                    .unwrap_or_default();
+                waiters.push(sender);
+                *waiting = Some(Waiting { edit, waiters });
+                Err(receiver)
+            }
+            None => {
+                slots.insert(key.clone(), None);
+                let in_flight = EditInFlight {
+                    slots: self,
+                    key,
+                    waiters: vec![],
+                    released: false,
+                };
+                Ok((edit, in_flight))
+            }
+        }
+    }
+}
+
+/// The slot of a key while its edits are sent. Dropping it releases the key,
+/// even if the send was cancelled, and the waiters then learn their edit was
+/// never sent.
+struct EditInFlight<'a, K: Eq + Hash + Clone, T, R: Clone> {
+    slots: &'a EditSlots<K, T, R>,
+    key: K,
+    /// Waiting for the outcome of the edit being sent.
+    waiters: Vec<oneshot::Sender<R>>,
+    released: bool,
+}
+
+impl<K: Eq + Hash + Clone, T, R: Clone> EditInFlight<'_, K, T, R> {
+    /// Passes on the outcome of the edit just sent. Returns the edit that
+    /// arrived in the meantime, if any, or releases the key.
+    fn finish(&mut self, outcome: R) -> Option<T> {
+        for waiter in self.waiters.drain(..) {
+            let _ = waiter.send(outcome.clone());
+        }
+
+        let mut slots = self
+            .slots
+            .slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match slots.get_mut(&self.key).and_then(Option::take) {
+            Some(Waiting { edit, waiters }) => {
+                self.waiters = waiters;
+                Some(edit)
+            }
+            None => {
+                slots.remove(&self.key);
+                self.released = true;
+                None
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, T, R: Clone> Drop for EditInFlight<'_, K, T, R> {
+    fn drop(&mut self) {
+        if !self.released {
+            self.slots
+                .slots
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&self.key);
+        }
+    }
+}
+
+/// Counts a request as in flight for as long as it's alive.
+struct InFlight<'a>(&'a AtomicU64);
+
+impl<'a> InFlight<'a> {
+    fn start(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct Outbound {
+    http: Arc<Http>,
+    global_bucket: GlobalBucket,
+    pending_edits: EditSlots<(ChannelId, MessageId), EditMessage, EditOutcome>,
+    metrics: Arc<OutboundMetrics>,
+}
+
+impl Outbound {
+    pub(crate) fn new(
+        bot_token: &str,
+        application_id: ApplicationId,
+        proxy: Option<&str>,
+        requests_per_second: u32,
+    ) -> Self {
+        let metrics = Arc::new(OutboundMetrics::default());
+        let mut builder = HttpBuilder::new(bot_token).application_id(application_id);
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy);
+        }
+        let mut http = builder.build();
+        if let Some(ratelimiter) = http.ratelimiter.as_mut() {
+            let metrics = metrics.clone();
+            ratelimiter.set_ratelimit_callback(Box::new(move |info| {
+                metrics.record_rate_limit(&info);
+            }));
+        }
+
+        Self {
+            http: Arc::new(http),
+            global_bucket: GlobalBucket::new(requests_per_second),
+            pending_edits: EditSlots::new(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> &OutboundMetrics {
+        &self.metrics
+    }
+
+    /// Sends a request once the global bucket allows it, e.g.
+    /// `outbound.send(|http| async move { channel_id.say(&http, "hi").await })`.
+    pub(crate) async fn send<T, F, Fut>(&self, request: F) -> serenity::Result<T>
+    where
+        F: FnOnce(Arc<Http>) -> Fut,
+        Fut: Future<Output = serenity::Result<T>>,
+    {
+        let waited = self.global_bucket.acquire().await;
+        if !waited.is_zero() {
+            self.metrics.throttled.fetch_add(1, Ordering::Relaxed);
+            self.metrics
+                .throttled_millis
+                .fetch_add(waited.as_millis() as u64, Ordering::Relaxed);
+        }
+
+        self.metrics.requests.fetch_add(1, Ordering::Relaxed);
+        let in_flight = InFlight::start(&self.metrics.in_flight);
+        let result = request(self.http.clone()).await;
+        drop(in_flight);
+        if result.is_err() {
+            self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    /// Edits a message, coalescing with other edits to it. Returns once the
+    /// edit is sent, or once a newer edit replacing it is. The errors of
+    /// coalesced edits are shared as `Arc<serenity::Error>`.
+    pub(crate) async fn edit_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        edit: EditMessage,
+    ) -> Result<()> {
+        let key = (channel_id, message_id);
+        let (mut edit, mut in_flight) = match self.pending_edits.begin(key, edit) {
+            Ok(send_now) => send_now,
+            Err(outcome) => {
+                self.metrics.coalesced_edits.fetch_add(1, Ordering::Relaxed);
+                return match outcome.await {
+                    Ok(outcome) => outcome.map_err(Into::into),
+                    Err(_) => Err(anyhow!("edit was cancelled before it was sent")),
+                };
+            }
+        };
+
+        loop {
+            let outcome: EditOutcome = self
+                .send(|http| async move { channel_id.edit_message(&http, message_id, edit).await })
+                .await
+                .map(|_| ())
+                .map_err(Arc::new);
+
+            // A newer edit supersedes this one whether or not it went through.
+            match in_flight.finish(outcome.clone()) {
+                Some(next) => edit = next,
+                None => return outcome.map_err(Into::into),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::outbound::{EditSlots, Outbound, GLOBAL_RATE_LIMIT};
+    use serenity::all::ApplicationId;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::sync::oneshot::error::TryRecvError;
+
+    #[test]
+    fn waiting_edits_get_the_outcome_of_the_newest() {
+        let slots = EditSlots::new();
+
+        let (edit, mut in_flight) = slots.begin(1, "first").ok().unwrap();
+        assert_eq!(edit, "first");
+        let mut second = slots.begin(1, "second").err().unwrap();
+        let mut third = slots.begin(1, "third").err().unwrap();
+        assert!(slots.begin(2, "other").is_ok());
+
+        assert_eq!(in_flight.finish(Err("first failed")), Some("third"));
+        // The second edit was replaced, so it goes with the third one.
+        assert_eq!(second.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(in_flight.finish(Ok(())), None);
+        assert_eq!(second.try_recv(), Ok(Ok(())));
+        assert_eq!(third.try_recv(), Ok(Ok(())));
+        // The key was released, so the next edit is sent right away.
+        assert!(slots.begin(1, "fourth").is_ok());
+    }
+
+    #[test]
+    fn cancelled_edit_releases_its_slot() {
+        let slots = EditSlots::<_, _, Result<(), ()>>::new();
+
+        let (_, in_flight) = slots.begin(1, "first").ok().unwrap();
+        let mut waiting = slots.begin(1, "second").err().unwrap();
+        drop(in_flight);
+
+        assert_eq!(waiting.try_recv(), Err(TryRecvError::Closed));
+        assert!(slots.begin(1, "third").is_ok());
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_leaves_the_in_flight_gauge() {
+        let outbound = Outbound::new("token", ApplicationId::new(1), None, GLOBAL_RATE_LIMIT);
+
+        let request = outbound.send(|_| std::future::pending::<serenity::Result<()>>());
+        assert!(tokio::time::timeout(Duration::from_millis(10), request)
+            .await
+            .is_err());
+        assert_eq!(outbound.metrics().in_flight.load(Ordering::Relaxed), 0);
+    }
+}
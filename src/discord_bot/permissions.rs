@@ -0,0 +1,200 @@
+use crate::database::DbModel;
+use crate::discord_bot::error::UserError;
+use crate::discord_bot::locale::{self, tr};
+use crate::discord_bot::DiscordBotState;
+use crate::Result;
+use anyhow::anyhow;
+use rkyv::{Archive, Deserialize, Serialize};
+use serenity::all::CommandInteraction;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// What a member may do with the generators of a guild. Each level includes
+/// everything the lower ones may do.
+#[derive(Archive, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub(crate) enum AccessLevel {
+    /// Look at generators and subscribe to reminders.
+    Viewer = 0,
+    /// Add and refuel generators.
+    Refueller = 1,
+    /// Post lists, configure alerts and permissions, and purge generators.
+    Admin = 2,
+}
+
+impl AccessLevel {
+    pub(crate) const ALL: [AccessLevel; 3] = [
+        AccessLevel::Viewer,
+        AccessLevel::Refueller,
+        AccessLevel::Admin,
+    ];
+
+    /// The value used for this level in Discord command choices.
+    pub(crate) const fn choice_value(&self) -> &'static str {
+        match self {
+            AccessLevel::Viewer => "viewer",
+            AccessLevel::Refueller => "refueller",
+            AccessLevel::Admin => "admin",
+        }
+    }
+
+    /// The level needed to run the `/gen` subcommand at `path`, such as `["alerts", "set"]`.
+    pub(crate) fn required_for(path: &[&str]) -> Self {
+        match path {
+            ["map" | "subscribe" | "unsubscribe" | "reminders", ..] => AccessLevel::Viewer,
+            ["new" | "refuel", ..] => AccessLevel::Refueller,
+            // Anything unknown is locked down rather than left open.
+            _ => AccessLevel::Admin,
+        }
+    }
+}
+
+impl Display for AccessLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccessLevel::Viewer => write!(f, "Viewer"),
+            AccessLevel::Refueller => write!(f, "Refueller"),
+            AccessLevel::Admin => write!(f, "Admin"),
+        }
+    }
+}
+
+impl FromStr for AccessLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        AccessLevel::ALL
+            .into_iter()
+            .find(|level| level.choice_value() == s)
+            .ok_or_else(|| anyhow!("unknown access level: {}", s))
+    }
+}
+
+/// The roles granted each access level in a guild.
+///
+/// A level without roles is open to every member, except for admin, which is
+/// then limited to members with the Manage Server permission. Those members
+/// are always admins so a guild can't lock itself out.
+#[derive(Archive, Serialize, Deserialize, Default)]
+pub(crate) struct GuildPermissions {
+    pub(crate) guild_id: u64,
+    pub(crate) viewer_roles: Vec<u64>,
+    pub(crate) refueller_roles: Vec<u64>,
+    pub(crate) admin_roles: Vec<u64>,
+}
+
+impl GuildPermissions {
+    pub(crate) fn new(guild_id: u64) -> Self {
+        Self {
+            guild_id,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn roles(&self, level: AccessLevel) -> &[u64] {
+        match level {
+            AccessLevel::Viewer => &self.viewer_roles,
+            AccessLevel::Refueller => &self.refueller_roles,
+            AccessLevel::Admin => &self.admin_roles,
+        }
+    }
+
+    pub(crate) fn roles_mut(&mut self, level: AccessLevel) -> &mut Vec<u64> {
+        match level {
+            AccessLevel::Viewer => &mut self.viewer_roles,
+            AccessLevel::Refueller => &mut self.refueller_roles,
+            AccessLevel::Admin => &mut self.admin_roles,
+        }
+    }
+
+    /// Checks whether a member with `member_roles` may act at `required` level.
+    pub(crate) fn allows(
+        &self,
+        required: AccessLevel,
+        member_roles: &[u64],
+        is_manager: bool,
+    ) -> bool {
+        if is_manager {
+            return true;
+        }
+
+        // Any role of the required level or a higher one grants access.
+        AccessLevel::ALL
+            .into_iter()
+            .filter(|level| *level >= required)
+            .any(|level| {
+                let roles = self.roles(level);
+                if roles.is_empty() {
+                    level == required && level != AccessLevel::Admin
+                } else {
+                    roles.iter().any(|role| member_roles.contains(role))
+                }
+            })
+    }
+}
+
+/// Fails with a [`UserError`] if the member running `command` may not run the
+/// `/gen` subcommand at `path`.
+pub(crate) async fn authorize(
+    state: &DiscordBotState,
+    command: &CommandInteraction,
+    path: &[&str],
+) -> Result<()> {
+    let (Some(guild_id), Some(member)) = (command.guild_id, command.member.as_ref()) else {
+        return Err(UserError(tr!(&command.locale, "error.guild-only")).into());
+    };
+
+    let required = AccessLevel::required_for(path);
+    let trx = state.database.start_trx()?;
+    let permissions = GuildPermissions::get(&trx, &guild_id.get())
+        .await?
+        .unwrap_or_else(|| GuildPermissions::new(guild_id.get()));
+
+    let member_roles: Vec<u64> = member.roles.iter().map(|role| role.get()).collect();
+    let is_manager = member
+        .permissions
+        .is_some_and(|permissions| permissions.administrator() || permissions.manage_guild());
+
+    if permissions.allows(required, &member_roles, is_manager) {
+        Ok(())
+    } else {
+        let level = locale::access_level_name(&command.locale, required);
+        Err(UserError(tr!(&command.locale, "error.access-denied", level = level)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::permissions::{AccessLevel, GuildPermissions};
+
+    const VIEWER_ROLE: u64 = 1;
+    const REFUELLER_ROLE: u64 = 2;
+    const ADMIN_ROLE: u64 = 3;
+
+    #[test]
+    fn unconfigured_guild() {
+        let permissions = GuildPermissions::new(0);
+
+        assert!(permissions.allows(AccessLevel::Viewer, &[], false));
+        assert!(permissions.allows(AccessLevel::Refueller, &[], false));
+        assert!(!permissions.allows(AccessLevel::Admin, &[], false));
+        assert!(permissions.allows(AccessLevel::Admin, &[], true));
+    }
+
+    #[test]
+    fn higher_levels_include_lower_ones() {
+        let permissions = GuildPermissions {
+            guild_id: 0,
+            viewer_roles: vec![VIEWER_ROLE],
+            refueller_roles: vec![REFUELLER_ROLE],
+            admin_roles: vec![ADMIN_ROLE],
+        };
+
+        assert!(!permissions.allows(AccessLevel::Viewer, &[], false));
+        assert!(permissions.allows(AccessLevel::Viewer, &[VIEWER_ROLE], false));
+        assert!(!permissions.allows(AccessLevel::Refueller, &[VIEWER_ROLE], false));
+        assert!(permissions.allows(AccessLevel::Refueller, &[ADMIN_ROLE], false));
+        assert!(permissions.allows(AccessLevel::Viewer, &[REFUELLER_ROLE], false));
+        assert!(!permissions.allows(AccessLevel::Admin, &[REFUELLER_ROLE], false));
+    }
+}
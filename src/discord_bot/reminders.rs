@@ -0,0 +1,281 @@
+use crate::database::models::GeneratorKey;
+use crate::database::{AsKey, DbModel};
+use crate::discord_bot::alerts::{self, newly_crossed, render_warning};
+use crate::discord_bot::jobs::JobOutcome;
+use crate::discord_bot::locale::DEFAULT_LOCALE;
+use crate::discord_bot::render;
+use crate::discord_bot::DiscordBotState;
+use crate::types::coordinates::ArkMap;
+use crate::types::tracking::TekGenerator;
+use crate::types::util::DateTime;
+use crate::Result;
+use rkyv::{Archive, Deserialize, Serialize};
+use serenity::all::{CreateMessage, UserId};
+use serenity::http::HttpError;
+use tracing::{debug, info};
+
+/// Discord's error code for a user who doesn't accept direct messages from the bot.
+const CANNOT_SEND_TO_USER: isize = 50007;
+
+/// A local time window in which no reminders are sent.
+#[derive(Archive, Serialize, Deserialize, Clone, Copy)]
+pub(crate) struct QuietHours {
+    /// Minutes after local midnight.
+    pub(crate) start: u16,
+    /// Minutes after local midnight, may be before `start` to span midnight.
+    pub(crate) end: u16,
+    pub(crate) utc_offset_minutes: i16,
+}
+
+impl QuietHours {
+    pub(crate) fn contains(&self, now: &DateTime) -> bool {
+        let local_minutes = now.timestamp_secs().div_euclid(60) + self.utc_offset_minutes as i64;
+        let minute_of_day = local_minutes.rem_euclid(24 * 60) as u16;
+
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute_of_day)
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct UserSettings {
+    pub(crate) user_id: u64,
+    pub(crate) opted_out: bool,
+    pub(crate) quiet_hours: Option<QuietHours>,
+    /// Remaining fuel in seconds at which to remind, in descending order. Zero means empty.
+    pub(crate) thresholds: Vec<u64>,
+    /// The only `CheckReminders` job allowed to act for this user.
+    pub(crate) check_job_id: u64,
+    /// The locale of the user's latest command, used for their reminders.
+    pub(crate) locale: String,
+}
+
+impl UserSettings {
+    pub(crate) fn new(user_id: u64) -> Result<Self> {
+        Ok(Self {
+            user_id,
+            opted_out: false,
+            quiet_hours: None,
+            thresholds: alerts::parse_thresholds(alerts::DEFAULT_THRESHOLDS)?,
+            check_job_id: 0,
+            locale: DEFAULT_LOCALE.to_string(),
+        })
+    }
+}
+
+#[derive(Archive, Serialize, Deserialize, Clone, PartialEq)]
+#[repr(u8)]
+pub(crate) enum SubscriptionTarget {
+    Generator { server: String, id: u64 } = 0,
+    Map { server: String, map: ArkMap } = 1,
+}
+
+impl SubscriptionTarget {
+    fn matches(&self, generator: &TekGenerator) -> bool {
+        match self {
+            SubscriptionTarget::Generator { server, id } => {
+                generator.server() == server && generator.id() == *id
+            }
+            SubscriptionTarget::Map { server, map } => {
+                generator.server() == server && generator.map() == *map
+            }
+        }
+    }
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    pub(crate) user_id: u64,
+    pub(crate) guild_id: u64,
+    pub(crate) target: SubscriptionTarget,
+}
+
+/// Subscriptions are grouped by user, so a reminder check is a single prefix read.
+pub(crate) struct SubscriptionKey {
+    pub(crate) user_id: u64,
+    pub(crate) guild_id: u64,
+    pub(crate) target: SubscriptionTarget,
+}
+
+impl AsKey for SubscriptionKey {
+    fn as_key(&self) -> Vec<u8> {
+        let mut key = [self.user_id.as_key(), self.guild_id.as_key()].concat();
+        match &self.target {
+            SubscriptionTarget::Generator { server, id } => {
+                key.push(0);
+                key.extend_from_slice(server.as_bytes());
+                key.push(0);
+                key.extend_from_slice(&id.as_key());
+            }
+            SubscriptionTarget::Map { server, map } => {
+                key.push(1);
+                key.extend_from_slice(server.as_bytes());
+                key.push(0);
+                key.push(*map as u8);
+            }
+        }
+
+        key
+    }
+}
+
+/// Which thresholds already fired for a user and generator during its current fuel cycle.
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct ReminderState {
+    pub(crate) user_id: u64,
+    pub(crate) guild_id: u64,
+    pub(crate) server: String,
+    pub(crate) generator_id: u64,
+    pub(crate) last_filled: DateTime,
+    pub(crate) fired: Vec<u64>,
+}
+
+pub(crate) struct ReminderStateKey {
+    pub(crate) user_id: u64,
+    pub(crate) generator: GeneratorKey,
+}
+
+impl AsKey for ReminderStateKey {
+    fn as_key(&self) -> Vec<u8> {
+        [self.user_id.as_key(), self.generator.as_key()].concat()
+    }
+}
+
+fn is_dm_refused(err: &serenity::Error) -> bool {
+    matches!(
+        err,
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+            if response.error.code == CANNOT_SEND_TO_USER
+    )
+}
+
+/// Sends a direct message for every subscribed generator that crossed one of
+/// the user's thresholds.
+pub(crate) async fn check_reminders(
+    state: &DiscordBotState,
+    job_id: u64,
+    user_id: u64,
+) -> Result<JobOutcome> {
+    let trx = state.database.start_trx()?;
+    let settings = match UserSettings::get(&trx, &user_id).await? {
+        Some(settings) if settings.check_job_id == job_id && !settings.opted_out => settings,
+        _ => {
+            debug!("user opted out or the checker was replaced, not rescheduling");
+            return Ok(JobOutcome::Finished);
+        }
+    };
+    let subscriptions = Subscription::get_all(&trx, &user_id.as_key()).await?;
+    if subscriptions.is_empty() {
+        return Ok(JobOutcome::Finished);
+    }
+
+    let now = DateTime::now();
+    if settings
+        .quiet_hours
+        .is_some_and(|quiet_hours| quiet_hours.contains(&now))
+    {
+        // Nothing is marked as fired, so the most urgent reminder goes out afterwards.
+        return Ok(JobOutcome::Reschedule(alerts::CHECK_INTERVAL));
+    }
+
+    let mut pending = vec![];
+    let mut checked_guilds = vec![];
+    for guild_id in subscriptions
+        .iter()
+        .map(|subscription| subscription.guild_id)
+    {
+        if checked_guilds.contains(&guild_id) {
+            continue;
+        }
+        checked_guilds.push(guild_id);
+
+        let generators = TekGenerator::get_all(&trx, &guild_id.as_key()).await?;
+        for generator in generators.into_iter().filter(|generator| {
+            subscriptions.iter().any(|subscription| {
+                subscription.guild_id == guild_id && subscription.target.matches(generator)
+            })
+        }) {
+            let fuel = generator.current_fuel();
+            let state_key = ReminderStateKey {
+                user_id,
+                generator: generator.key(),
+            };
+            let mut reminder_state = ReminderState::get(&trx, &state_key)
+                .await?
+                .filter(|reminder_state| reminder_state.last_filled == fuel.last_filled())
+                .unwrap_or_else(|| ReminderState {
+                    user_id,
+                    guild_id,
+                    server: generator.server().to_string(),
+                    generator_id: generator.id(),
+                    last_filled: fuel.last_filled(),
+                    fired: vec![],
+                });
+
+            if let Some(threshold) = newly_crossed(
+                &settings.thresholds,
+                &mut reminder_state.fired,
+                fuel.remaining(&now),
+            ) {
+                let content = render_warning(&settings.locale, &generator, threshold);
+                let embed = render::generator_embed(&settings.locale, &generator, &now);
+                pending.push((content, embed, reminder_state));
+            }
+        }
+    }
+    drop(trx);
+
+    for (content, embed, reminder_state) in pending {
+        let message = CreateMessage::new().content(content).embed(embed);
+        let user = UserId::new(user_id);
+        match state
+            .outbound
+            .send(|http| async move { user.direct_message(&http, message).await })
+            .await
+        {
+            Ok(_) => {}
+            // Retrying won't help until the user changes their privacy settings.
+            Err(err) if is_dm_refused(&err) => {
+                info!("user does not accept direct messages, skipping reminder");
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        state
+            .database
+            .transact(async |trx| reminder_state.put(trx))
+            .await?;
+    }
+
+    Ok(JobOutcome::Reschedule(alerts::CHECK_INTERVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::reminders::QuietHours;
+    use crate::types::util::DateTime;
+
+    const MINUTE_MILLIS: i64 = 60 * 1000;
+
+    #[test]
+    fn quiet_hours_spanning_midnight() {
+        // 22:00 to 07:00 at UTC+2.
+        let quiet_hours = QuietHours {
+            start: 22 * 60,
+            end: 7 * 60,
+            utc_offset_minutes: 120,
+        };
+
+        // 21:30 UTC is 23:30 local.
+        assert!(quiet_hours.contains(&DateTime::from((21 * 60 + 30) * MINUTE_MILLIS)));
+        // 04:59 UTC is 06:59 local.
+        assert!(quiet_hours.contains(&DateTime::from((4 * 60 + 59) * MINUTE_MILLIS)));
+        // 05:00 UTC is 07:00 local.
+        assert!(!quiet_hours.contains(&DateTime::from(5 * 60 * MINUTE_MILLIS)));
+        // 12:00 UTC is 14:00 local.
+        assert!(!quiet_hours.contains(&DateTime::from(12 * 60 * MINUTE_MILLIS)));
+    }
+}
@@ -0,0 +1,266 @@
+//! Turns generators into Discord embeds, shared by list messages, alerts,
+//! reminders and command replies.
+
+pub(crate) mod map_image;
+
+use crate::discord_bot::locale::{self, tr};
+use crate::types::coordinates::ArkCoordinates;
+use crate::types::tracking::{TekGenerator, TrackedStructure};
+use crate::types::util::DateTime;
+use serenity::all::{CreateEmbed, CreateEmbedFooter, Timestamp};
+use std::time::Duration;
+
+const FUEL_BAR_WIDTH: usize = 10;
+/// Discord rejects embeds with more fields than this.
+const EMBED_FIELD_LIMIT: usize = 25;
+/// Discord rejects titles and field names longer than this, in characters.
+const EMBED_NAME_LIMIT: usize = 256;
+/// Discord rejects embeds whose title, description, field names and values
+/// and footer add up to more characters than this.
+const EMBED_TOTAL_LIMIT: usize = 6000;
+/// Generators with less fuel left than this are shown as running low.
+const LOW_FUEL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How urgently a generator needs fuel, which decides its color.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum FuelState {
+    Fueled,
+    Low,
+    Empty,
+}
+
+impl FuelState {
+    pub(crate) fn of(generator: &TekGenerator, now: &DateTime) -> Self {
+        let remaining = generator.current_fuel().remaining(now);
+
+        if remaining.is_zero() {
+            FuelState::Empty
+        } else if remaining < LOW_FUEL {
+            FuelState::Low
+        } else {
+            FuelState::Fueled
+        }
+    }
+
+    /// The color as `0xRRGGBB`, matching Discord's own green, yellow and red.
+    pub(crate) const fn color(&self) -> u32 {
+        match self {
+            FuelState::Fueled => 0x57F287,
+            FuelState::Low => 0xFEE75C,
+            FuelState::Empty => 0xED4245,
+        }
+    }
+}
+
+pub(crate) fn fuel_bar(fraction: f32, width: usize) -> String {
+    let filled = ((fraction.clamp(0.0, 1.0) * width as f32).ceil() as usize).min(width);
+
+    "█".repeat(filled) + &"░".repeat(width - filled)
+}
+
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let total_minutes = duration.as_secs() / 60;
+    let (days, hours, minutes) = (
+        total_minutes / (24 * 60),
+        total_minutes / 60 % 24,
+        total_minutes % 60,
+    );
+
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h {minutes}m"),
+    }
+}
+
+/// A timestamp every Discord client shows relative to its own clock, e.g. "in 2 hours".
+pub(crate) fn relative_timestamp(date_time: &DateTime) -> String {
+    format!("<t:{}:R>", date_time.timestamp_secs())
+}
+
+/// The ARK coordinates of a generator as shown in game, e.g. `78.7, 28.5`.
+pub(crate) fn format_coordinates(generator: &TekGenerator) -> String {
+    let coordinates = ArkCoordinates::from(generator.coords()).rounded();
+
+    format!(
+        "{:.1}, {:.1}",
+        coordinates.latitude(),
+        coordinates.longitude()
+    )
+}
+
+/// A progress bar with the fuel left and when it runs out.
+pub(crate) fn format_fuel(locale: &str, generator: &TekGenerator, now: &DateTime) -> String {
+    let fuel = generator.current_fuel();
+    let bar = fuel_bar(fuel.fraction_left(now), FUEL_BAR_WIDTH);
+    let runs_out = relative_timestamp(&fuel.runs_out_at());
+
+    if fuel.remaining(now).is_zero() {
+        tr!(locale, "render.fuel-empty", bar = bar, runs_out = runs_out)
+    } else {
+        tr!(
+            locale,
+            "render.fuel-left",
+            bar = bar,
+            remaining = format_duration(fuel.remaining(now)),
+            runs_out = runs_out
+        )
+    }
+}
+
+/// An embed describing a single generator.
+pub(crate) fn generator_embed(
+    locale: &str,
+    generator: &TekGenerator,
+    now: &DateTime,
+) -> CreateEmbed {
+    let location = tr!(
+        locale,
+        "render.location",
+        server = generator.server(),
+        map = locale::map_name(locale, generator.map())
+    );
+
+    CreateEmbed::new()
+        .title(truncate(generator.name(), EMBED_NAME_LIMIT))
+        .description(location)
+        .color(FuelState::of(generator, now).color())
+        .field(
+            tr!(locale, "render.fuel"),
+            format_fuel(locale, generator, now),
+            false,
+        )
+        .field(
+            tr!(locale, "render.coordinates"),
+            format_coordinates(generator),
+            true,
+        )
+}
+
+/// An embed listing `generators`, one field each, with the most urgent state as its color.
+pub(crate) fn generators_embed(
+    locale: &str,
+    title: String,
+    generators: &[TekGenerator],
+    now: &DateTime,
+) -> CreateEmbed {
+    let title = truncate(&title, EMBED_NAME_LIMIT);
+    let footer = tr!(locale, "render.updated");
+    // The description is only there when fields are left out, so room for
+    // it is kept for the longest it can get.
+    let more_description = tr!(locale, "list.more", count = generators.len());
+    let used = title.chars().count() + footer.chars().count() + more_description.chars().count();
+
+    let mut embed = CreateEmbed::new()
+        .title(title)
+        .footer(CreateEmbedFooter::new(footer));
+    if let Ok(timestamp) = Timestamp::from_unix_timestamp(now.timestamp_secs()) {
+        embed = embed.timestamp(timestamp);
+    }
+
+    let most_urgent = generators
+        .iter()
+        .map(|generator| FuelState::of(generator, now))
+        .max()
+        .unwrap_or(FuelState::Fueled);
+    embed = embed.color(most_urgent.color());
+
+    if generators.is_empty() {
+        return embed.description(tr!(locale, "list.empty"));
+    }
+
+    let fields = generators.iter().map(|generator| {
+        let value = format!(
+            "{}\n📍 {}",
+            format_fuel(locale, generator, now),
+            format_coordinates(generator)
+        );
+        (generator.name().to_string(), value)
+    });
+    let fields = fitting_fields(fields, used);
+    if fields.len() < generators.len() {
+        let hidden = generators.len() - fields.len();
+        embed = embed.description(tr!(locale, "list.more", count = hidden));
+    }
+
+    embed.fields(fields.into_iter().map(|(name, value)| (name, value, false)))
+}
+
+/// The leading `fields` that fit into an embed with `used` characters taken
+/// already, with their names cut to Discord's limit.
+fn fitting_fields(
+    fields: impl Iterator<Item = (String, String)>,
+    mut used: usize,
+) -> Vec<(String, String)> {
+    let mut fitting = vec![];
+    for (name, value) in fields.take(EMBED_FIELD_LIMIT) {
+        let name = truncate(&name, EMBED_NAME_LIMIT);
+        let len = name.chars().count() + value.chars().count();
+        if used + len > EMBED_TOTAL_LIMIT {
+            break;
+        }
+        used += len;
+        fitting.push((name, value));
+    }
+
+    fitting
+}
+
+/// `text` cut to `max_chars` characters, ending in `…` if it was longer.
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    text.chars().take(max_chars - 1).chain(['…']).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::render::{
+        fitting_fields, format_duration, fuel_bar, truncate, EMBED_FIELD_LIMIT, EMBED_NAME_LIMIT,
+        EMBED_TOTAL_LIMIT,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn fuel_bar_rounds_up() {
+        assert_eq!(fuel_bar(1.0, 4), "████");
+        assert_eq!(fuel_bar(0.3, 4), "██░░");
+        assert_eq!(fuel_bar(0.0, 4), "░░░░");
+        assert_eq!(fuel_bar(-1.0, 4), "░░░░");
+    }
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(format_duration(Duration::from_secs(59)), "0m");
+        assert_eq!(format_duration(Duration::from_secs(90 * 60)), "1h 30m");
+        assert_eq!(
+            format_duration(Duration::from_secs(26 * 60 * 60)),
+            "1d 2h 0m"
+        );
+    }
+
+    #[test]
+    fn fields_fit_discord_limits() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("abcdef", 4), "abc…");
+
+        let long_name = "x".repeat(1000);
+        let fields = fitting_fields(
+            std::iter::repeat((long_name, "value".to_string())).take(40),
+            0,
+        );
+        // 256 + 5 characters each, so 22 fit into 6000.
+        assert_eq!(fields.len(), EMBED_TOTAL_LIMIT / (EMBED_NAME_LIMIT + 5));
+        assert!(fields
+            .iter()
+            .all(|(name, _)| name.chars().count() == EMBED_NAME_LIMIT));
+
+        let fields = fitting_fields(
+            std::iter::repeat(("name".to_string(), "value".to_string())).take(40),
+            0,
+        );
+        assert_eq!(fields.len(), EMBED_FIELD_LIMIT);
+    }
+}
@@ -0,0 +1,235 @@
+//! Draws the generators of a map onto the in-game latitude/longitude grid.
+//!
+//! Everything is drawn from scratch, including the axis labels, so no map
+//! artwork or font files have to ship with the bot.
+
+use crate::discord_bot::render::FuelState;
+use crate::types::coordinates::{ArkCoordinates, ArkMap};
+use crate::types::tracking::{Generator, TekGenerator, TrackedStructure};
+use crate::types::util::DateTime;
+use crate::Result;
+use anyhow::Context;
+use tiny_skia::{Color, FillRule, Paint, PathBuilder, Pixmap, Rect, Stroke, Transform};
+
+pub(crate) const FILE_NAME: &str = "map.png";
+
+/// Pixels per degree of latitude or longitude.
+const PIXELS_PER_DEGREE: f32 = 8.0;
+/// Room around the grid for the axis labels.
+const MARGIN: f32 = 32.0;
+const GRID_STEP_DEGREES: u32 = 10;
+const GRID_SIZE: f32 = 100.0 * PIXELS_PER_DEGREE;
+const IMAGE_SIZE: u32 = (GRID_SIZE + 2.0 * MARGIN) as u32;
+const LABEL_SCALE: f32 = 2.0;
+const MARKER_RADIUS: f32 = 4.0;
+
+const BACKGROUND: (u8, u8, u8) = (0x2B, 0x2D, 0x31);
+const GRID_LINE: (u8, u8, u8) = (0x4E, 0x50, 0x58);
+const LABEL: (u8, u8, u8) = (0xB5, 0xBA, 0xC1);
+
+/// Digits in a 3x5 pixel font, one row per byte with the leftmost pixel in bit 2.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+fn paint((red, green, blue): (u8, u8, u8), alpha: u8) -> Paint<'static> {
+    let mut paint = Paint::default();
+    paint.set_color_rgba8(red, green, blue, alpha);
+    paint.anti_alias = true;
+
+    paint
+}
+
+fn fuel_color(state: FuelState) -> (u8, u8, u8) {
+    let [_, red, green, blue] = state.color().to_be_bytes();
+
+    (red, green, blue)
+}
+
+/// Where a latitude or longitude ends up on the image.
+fn to_pixels(degrees: f32) -> f32 {
+    MARGIN + degrees * PIXELS_PER_DEGREE
+}
+
+fn draw_number(pixmap: &mut Pixmap, number: u32, center_x: f32, center_y: f32) {
+    let digits: Vec<usize> = number
+        .to_string()
+        .bytes()
+        .map(|digit| (digit - b'0') as usize)
+        .collect();
+    // Each digit is 3 pixels wide with a 1 pixel gap.
+    let width = (digits.len() * 4 - 1) as f32 * LABEL_SCALE;
+    let left = center_x - width / 2.0;
+    let top = center_y - 5.0 * LABEL_SCALE / 2.0;
+    let paint = paint(LABEL, 0xFF);
+
+    for (index, digit) in digits.into_iter().enumerate() {
+        for (row, bits) in DIGITS[digit].into_iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                let x = left + (index * 4 + column) as f32 * LABEL_SCALE;
+                let y = top + row as f32 * LABEL_SCALE;
+                if let Some(rect) = Rect::from_xywh(x, y, LABEL_SCALE, LABEL_SCALE) {
+                    pixmap.fill_rect(rect, &paint, Transform::identity(), None);
+                }
+            }
+        }
+    }
+}
+
+fn draw_grid(pixmap: &mut Pixmap) -> Result<()> {
+    let mut grid = PathBuilder::new();
+    for step in (0..=100).step_by(GRID_STEP_DEGREES as usize) {
+        let offset = to_pixels(step as f32);
+        grid.move_to(offset, MARGIN);
+        grid.line_to(offset, MARGIN + GRID_SIZE);
+        grid.move_to(MARGIN, offset);
+        grid.line_to(MARGIN + GRID_SIZE, offset);
+
+        // Longitude along the top, latitude down the left, as on the in-game map.
+        draw_number(pixmap, step, offset, MARGIN / 2.0);
+        draw_number(pixmap, step, MARGIN / 2.0, offset);
+    }
+    let grid = grid.finish().context("empty grid path")?;
+
+    let stroke = Stroke {
+        width: 1.0,
+        ..Default::default()
+    };
+    pixmap.stroke_path(
+        &grid,
+        &paint(GRID_LINE, 0xFF),
+        &stroke,
+        Transform::identity(),
+        None,
+    );
+
+    Ok(())
+}
+
+fn draw_generator(pixmap: &mut Pixmap, generator: &TekGenerator, now: &DateTime) {
+    let coordinates = ArkCoordinates::from(generator.coords());
+    let x = to_pixels(coordinates.longitude());
+    let y = to_pixels(coordinates.latitude());
+    let range_degrees = generator.range() as f32 / generator.map().get_scale().scale;
+    let color = fuel_color(FuelState::of(generator, now));
+
+    if let Some(range) = PathBuilder::from_circle(x, y, range_degrees * PIXELS_PER_DEGREE) {
+        pixmap.fill_path(
+            &range,
+            &paint(color, 0x40),
+            FillRule::Winding,
+            Transform::identity(),
+            None,
+        );
+        let stroke = Stroke {
+            width: 1.5,
+            ..Default::default()
+        };
+        pixmap.stroke_path(
+            &range,
+            &paint(color, 0xC0),
+            &stroke,
+            Transform::identity(),
+            None,
+        );
+    }
+    if let Some(marker) = PathBuilder::from_circle(x, y, MARKER_RADIUS) {
+        pixmap.fill_path(
+            &marker,
+            &paint(color, 0xFF),
+            FillRule::Winding,
+            Transform::identity(),
+            None,
+        );
+    }
+}
+
+/// Renders `generators` on `map` as a PNG, colored like their embeds.
+pub(crate) fn render(map: ArkMap, generators: &[TekGenerator], now: &DateTime) -> Result<Vec<u8>> {
+    let mut pixmap = Pixmap::new(IMAGE_SIZE, IMAGE_SIZE).context("invalid image size")?;
+    let (red, green, blue) = BACKGROUND;
+    pixmap.fill(Color::from_rgba8(red, green, blue, 0xFF));
+
+    draw_grid(&mut pixmap)?;
+    for generator in generators.iter().filter(|generator| generator.map() == map) {
+        draw_generator(&mut pixmap, generator, now);
+    }
+
+    Ok(pixmap.encode_png()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::render::map_image::{render, IMAGE_SIZE};
+    use crate::types::coordinates::{ArkCoordinates, ArkMap};
+    use crate::types::fuel::{ElementOrShards, Fuel};
+    use crate::types::tracking::TekGenerator;
+    use crate::types::util::DateTime;
+
+    /// The smallest upload limit Discord applies to bots, in bytes.
+    const ATTACHMENT_LIMIT: usize = 10 * 1024 * 1024;
+
+    fn generator(
+        id: u64,
+        latitude: f32,
+        longitude: f32,
+        map: ArkMap,
+        elements: u32,
+    ) -> TekGenerator {
+        TekGenerator::new(
+            id,
+            1,
+            "PvE".to_string(),
+            format!("Generator {}", id),
+            ArkCoordinates::new(latitude, longitude, map).into(),
+            Fuel::new(ElementOrShards::new(elements, 0), DateTime::now()),
+        )
+    }
+
+    /// Width and height from the IHDR chunk.
+    fn dimensions(png: &[u8]) -> (u32, u32) {
+        let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+        let height = u32::from_be_bytes(png[20..24].try_into().unwrap());
+
+        (width, height)
+    }
+
+    #[test]
+    fn renders_png() {
+        let png = render(ArkMap::Island, &[], &DateTime::now()).unwrap();
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn draws_generators_of_the_map_only() {
+        let now = DateTime::now();
+        let empty = render(ArkMap::Island, &[], &now).unwrap();
+        let island = [
+            generator(1, 50.0, 50.0, ArkMap::Island, 10),
+            generator(2, 20.0, 70.0, ArkMap::Island, 0),
+            // Ranges reaching past the edges are cut off, not a failure.
+            generator(3, 0.0, 100.0, ArkMap::Island, 1),
+        ];
+        let with_generators = render(ArkMap::Island, &island, &now).unwrap();
+
+        assert_ne!(with_generators, empty);
+        assert_eq!(dimensions(&with_generators), (IMAGE_SIZE, IMAGE_SIZE));
+        assert!(with_generators.len() < ATTACHMENT_LIMIT);
+
+        let elsewhere = [generator(4, 50.0, 50.0, ArkMap::Aberration, 10)];
+        assert_eq!(render(ArkMap::Island, &elsewhere, &now).unwrap(), empty);
+    }
+}
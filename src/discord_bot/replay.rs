@@ -0,0 +1,81 @@
+use crate::types::util::DateTime;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Rejects interactions that are stale or were already delivered, so a
+/// captured request can't be resent to mutate generator data.
+///
+/// Seen interaction IDs are kept in memory, since a replayed request only gets
+/// through within the freshness window of the original. That makes replay
+/// protection per instance: with several instances behind a load balancer, a
+/// request replayed to another instance within the window is accepted. Route
+/// interactions to a single instance where that matters.
+pub(super) struct ReplayGuard {
+    max_age: Duration,
+    seen: Mutex<HashMap<u64, DateTime>>,
+}
+
+impl ReplayGuard {
+    pub(super) fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the signed `X-Signature-Timestamp`, in seconds, against the
+    /// freshness window. Clocks may be off in either direction.
+    pub(super) fn is_fresh(&self, timestamp_secs: i64, now: &DateTime) -> bool {
+        let timestamp = DateTime::from(timestamp_secs.saturating_mul(1000));
+        let skew = if timestamp > *now {
+            timestamp.duration_since(now)
+        } else {
+            now.duration_since(&timestamp)
+        };
+
+        skew <= self.max_age
+    }
+
+    /// Records the interaction ID, returning `false` if it was seen before.
+    pub(super) fn check_and_record(&self, interaction_id: u64, now: &DateTime) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // An ID can't come back fresh once both windows around it have passed.
+        let cutoff = now.saturating_sub(self.max_age * 2);
+        seen.retain(|_, seen_at| *seen_at >= cutoff);
+
+        match seen.entry(interaction_id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(*now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discord_bot::replay::ReplayGuard;
+    use crate::types::util::DateTime;
+    use std::time::Duration;
+
+    #[test]
+    fn reject_stale_and_replayed() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        let now = DateTime::from(1_700_000_000_000);
+
+        assert!(guard.is_fresh(1_700_000_000, &now));
+        assert!(guard.is_fresh(1_700_000_030, &now));
+        assert!(!guard.is_fresh(1_699_999_900, &now));
+
+        assert!(guard.check_and_record(42, &now));
+        assert!(!guard.check_and_record(42, &now));
+
+        // Forgotten once it could no longer be fresh anyway.
+        let later = now.saturating_add(Duration::from_secs(121));
+        assert!(guard.check_and_record(42, &later));
+    }
+}
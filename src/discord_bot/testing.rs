@@ -0,0 +1,204 @@
+//! Helpers for running whole interaction flows offline: a key pair to sign
+//! interactions like Discord does, and a bot wired to [`MockDiscord`].
+
+pub(crate) mod mock_discord;
+
+use crate::database::Database;
+use crate::discord_bot::error::InteractionError;
+use crate::discord_bot::testing::mock_discord::{MockDiscord, APPLICATION_ID};
+use crate::discord_bot::{handle_interaction, DiscordBotConfig, DiscordBotState};
+use crate::types::coordinates::{ArkCoordinates, ArkMap};
+use crate::types::fuel::{ElementOrShards, Fuel};
+use crate::types::tracking::TekGenerator;
+use crate::types::util::DateTime;
+use crate::Result;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderValue};
+use clap::Parser;
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub(crate) const GUILD_ID: u64 = 200;
+pub(crate) const CHANNEL_ID: u64 = 201;
+pub(crate) const USER_ID: u64 = 202;
+
+/// Signs interactions the way Discord does, for a fresh key pair.
+pub(crate) struct InteractionSigner {
+    key: SigningKey,
+}
+
+impl InteractionSigner {
+    pub(crate) fn new() -> Self {
+        Self {
+            key: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    /// The public key as configured with `--public-key`.
+    pub(crate) fn public_key_hex(&self) -> String {
+        const_hex::encode(self.key.verifying_key().to_bytes())
+    }
+
+    /// The headers Discord would send along with `body` at `timestamp_secs`.
+    pub(crate) fn sign(&self, body: &[u8], timestamp_secs: i64) -> HeaderMap {
+        let timestamp = timestamp_secs.to_string();
+        let message = [timestamp.as_bytes(), body].concat();
+        let signature = const_hex::encode(self.key.sign(&message).to_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Signature-Ed25519",
+            HeaderValue::from_str(&signature).expect("hex is a valid header value"),
+        );
+        headers.insert(
+            "X-Signature-Timestamp",
+            HeaderValue::from_str(&timestamp).expect("digits are a valid header value"),
+        );
+
+        headers
+    }
+}
+
+/// A bot in HTTP mode talking to a [`MockDiscord`], with its own database.
+pub(crate) struct TestBot {
+    pub(crate) state: Arc<DiscordBotState>,
+    pub(crate) discord: MockDiscord,
+    pub(crate) signer: InteractionSigner,
+    database_dir: PathBuf,
+}
+
+impl TestBot {
+    pub(crate) async fn start() -> Result<Self> {
+        let discord = MockDiscord::start().await?;
+        let signer = InteractionSigner::new();
+        let database_dir =
+            std::env::temp_dir().join(format!("genny-test-{:016x}", rand::random::<u64>()));
+        let database = Arc::new(Database::open_rocksdb(&database_dir)?);
+
+        let config = DiscordBotConfig::try_parse_from([
+            "genny",
+            "--public-key",
+            &signer.public_key_hex(),
+            "--bot-token",
+            "test-token",
+            "--application-id",
+            &APPLICATION_ID.to_string(),
+            "--discord-api-proxy",
+            &discord.url(),
+        ])?;
+        let state = Arc::new(DiscordBotState::configure(config, database)?);
+
+        Ok(Self {
+            state,
+            discord,
+            signer,
+            database_dir,
+        })
+    }
+
+    /// Signs `interaction` and hands it to [`handle_interaction`], returning
+    /// the initial response as JSON.
+    pub(crate) async fn interact(
+        &self,
+        interaction: &Value,
+    ) -> std::result::Result<Value, InteractionError> {
+        let body = serde_json::to_vec(interaction).expect("JSON values serialize");
+        let headers = self.signer.sign(&body, DateTime::now().timestamp_secs());
+
+        self.send_signed(headers, body).await
+    }
+
+    pub(crate) async fn send_signed(
+        &self,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> std::result::Result<Value, InteractionError> {
+        let response =
+            handle_interaction(State(self.state.clone()), headers, Bytes::from(body)).await?;
+
+        Ok(serde_json::to_value(response.0).expect("responses serialize"))
+    }
+
+    /// Waits for every deferred command to finish.
+    pub(crate) async fn settle(&self) {
+        self.state.task_tracker.close();
+        self.state.task_tracker.wait().await;
+        self.state.task_tracker.reopen();
+    }
+}
+
+impl Drop for TestBot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.database_dir);
+    }
+}
+
+/// A Tek generator of [`GUILD_ID`] on The Island of the `PvE` server,
+/// filled with `elements` raw element at `last_filled`.
+pub(crate) fn tek_generator(
+    id: u64,
+    name: &str,
+    elements: u32,
+    last_filled: DateTime,
+) -> TekGenerator {
+    TekGenerator::new(
+        id,
+        GUILD_ID,
+        "PvE".to_string(),
+        name.to_string(),
+        ArkCoordinates::new(50.0, 50.0, ArkMap::Island).into(),
+        Fuel::new(ElementOrShards::new(elements, 0), last_filled),
+    )
+}
+
+pub(crate) fn ping_interaction(id: u64) -> Value {
+    json!({
+        "id": id.to_string(),
+        "application_id": APPLICATION_ID.to_string(),
+        "type": 1,
+        "token": format!("token-{}", id),
+        "version": 1,
+    })
+}
+
+/// A `/gen` command from a guild member with the Manage Server permission.
+/// `options` are the options of `/gen`, such as a subcommand.
+pub(crate) fn gen_command_interaction(id: u64, options: Value) -> Value {
+    json!({
+        "id": id.to_string(),
+        "application_id": APPLICATION_ID.to_string(),
+        "type": 2,
+        "token": format!("token-{}", id),
+        "version": 1,
+        "guild_id": GUILD_ID.to_string(),
+        "channel_id": CHANNEL_ID.to_string(),
+        "locale": "en-US",
+        "guild_locale": "en-US",
+        "app_permissions": "0",
+        "entitlements": [],
+        "member": {
+            "user": {
+                "id": USER_ID.to_string(),
+                "username": "survivor",
+                "discriminator": "0",
+                "global_name": null,
+                "avatar": null,
+            },
+            "roles": [],
+            "joined_at": "2024-01-01T00:00:00.000000+00:00",
+            "deaf": false,
+            "mute": false,
+            "flags": 0,
+            "permissions": "32",
+        },
+        "data": {
+            "id": "300",
+            "name": "gen",
+            "type": 1,
+            "options": options,
+        },
+    })
+}
@@ -0,0 +1,180 @@
+//! A local stand-in for the parts of the Discord HTTP API the bot uses.
+//!
+//! Every request is recorded and answered with a plausible response, so
+//! serenity's parsing succeeds and tests can assert on what was sent.
+
+use crate::Result;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+pub(crate) const APPLICATION_ID: u64 = 100;
+pub(crate) const BOT_USER_ID: u64 = 101;
+const API_PREFIX: &str = "/api/v10/";
+
+#[derive(Clone, Debug)]
+pub(crate) struct RecordedRequest {
+    pub(crate) method: Method,
+    /// The path after `/api/v10/`, e.g. `channels/1/messages`.
+    pub(crate) path: String,
+    /// The JSON body, or `Value::Null` for empty and multipart bodies.
+    pub(crate) body: Value,
+}
+
+#[derive(Default)]
+struct MockState {
+    requests: Mutex<Vec<RecordedRequest>>,
+    next_id: AtomicU64,
+}
+
+pub(crate) struct MockDiscord {
+    address: SocketAddr,
+    state: Arc<MockState>,
+    shutdown: CancellationToken,
+}
+
+impl MockDiscord {
+    pub(crate) async fn start() -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(MockState {
+            next_id: AtomicU64::new(1000),
+            ..Default::default()
+        });
+        let shutdown = CancellationToken::new();
+
+        let router = Router::new()
+            .fallback(handle_request)
+            .with_state(state.clone());
+        let stopped = shutdown.clone();
+        tokio::spawn(async move {
+            axum::serve(listener, router)
+                .with_graceful_shutdown(stopped.cancelled_owned())
+                .await
+        });
+
+        Ok(Self {
+            address,
+            state,
+            shutdown,
+        })
+    }
+
+    /// The base URL to pass as the Discord API proxy.
+    pub(crate) fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    pub(crate) fn requests(&self) -> Vec<RecordedRequest> {
+        self.state
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The first recorded request with `method` whose path ends with `path_suffix`.
+    pub(crate) fn find(&self, method: Method, path_suffix: &str) -> Option<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .find(|request| request.method == method && request.path.ends_with(path_suffix))
+    }
+}
+
+impl Drop for MockDiscord {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+fn user(id: u64) -> Value {
+    json!({
+        "id": id.to_string(),
+        "username": "genny",
+        "discriminator": "0",
+        "global_name": null,
+        "avatar": null,
+        "bot": true,
+    })
+}
+
+fn message(id: u64, channel_id: &str, body: &Value) -> Value {
+    json!({
+        "id": id.to_string(),
+        "channel_id": channel_id,
+        "author": user(BOT_USER_ID),
+        "content": body.get("content").cloned().unwrap_or(json!("")),
+        "timestamp": "2024-01-01T00:00:00.000000+00:00",
+        "edited_timestamp": null,
+        "tts": false,
+        "mention_everyone": false,
+        "mentions": [],
+        "mention_roles": [],
+        "attachments": [],
+        "embeds": body.get("embeds").cloned().unwrap_or(json!([])),
+        "pinned": false,
+        "type": 0,
+    })
+}
+
+async fn handle_request(
+    State(state): State<Arc<MockState>>,
+    method: Method,
+    uri: Uri,
+    body: Bytes,
+) -> Response {
+    let Some(path) = uri.path().strip_prefix(API_PREFIX) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    state
+        .requests
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(RecordedRequest {
+            method: method.clone(),
+            path: path.to_string(),
+            body: body.clone(),
+        });
+
+    let segments: Vec<&str> = path.split('/').collect();
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+
+    match (method, segments.as_slice()) {
+        (Method::POST, ["interactions", _, _, "callback"]) => {
+            StatusCode::NO_CONTENT.into_response()
+        }
+        (Method::PUT, ["applications", _, "commands"]) => Json(json!([])).into_response(),
+        (Method::POST, ["users", "@me", "channels"]) => Json(json!({
+            "id": id.to_string(),
+            "type": 1,
+            "last_message_id": null,
+            "recipients": [user(id)],
+        }))
+        .into_response(),
+        (Method::POST, ["channels", channel_id, "messages"]) => {
+            Json(message(id, channel_id, &body)).into_response()
+        }
+        (Method::PATCH, ["channels", channel_id, "messages", message_id]) => {
+            Json(message(message_id.parse().unwrap_or(id), channel_id, &body)).into_response()
+        }
+        // Follow-ups and edits of the original interaction response.
+        (Method::POST, ["webhooks", _, _]) | (Method::PATCH, ["webhooks", _, _, "messages", _]) => {
+            Json(message(id, "1", &body)).into_response()
+        }
+        (Method::DELETE, _) => StatusCode::NO_CONTENT.into_response(),
+        _ => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": "404: Not Found", "code": 0 })),
+        )
+            .into_response(),
+    }
+}
@@ -1,78 +1,301 @@
-use crate::config::TlsConfig;
+pub(crate) mod acme;
+pub(crate) mod cert_resolver;
+pub(crate) mod client_addr;
+pub(crate) mod client_auth;
+pub(crate) mod listener;
+pub(crate) mod redirect;
+
+use crate::admin_panel;
+use crate::config::{AcmeChallenge, AcmeConfig, CliConfig, TlsConfig, TlsFiles};
+use crate::database::Database;
+use crate::discord_bot::DiscordBotState;
+use crate::server::acme::{CertificateManager, Challenges, ACME_TLS_ALPN};
+use crate::server::cert_resolver::{CertResolver, PemWatcher};
+use crate::server::client_addr::{read_proxy_header, resolve_forwarded_client, IpNetwork};
+use crate::server::client_auth::{
+    client_verifier, require_client_identity, ClientIdentity, ADMIN_PATH,
+};
+use crate::server::listener::{bind_tcp_listeners, Listener, Stream};
+use crate::server::redirect::{hsts_header, https_redirect_router, DEFAULT_HSTS_MAX_AGE_SECS};
 use crate::types::util::DateTime;
-use crate::{Result, ServerState};
-use anyhow::Context;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use axum::body::Body;
 use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
 use axum::extract::ConnectInfo;
 use axum::handler::Handler;
-use axum::http::Request;
-use axum::middleware::AddExtension;
+use axum::http::{header, HeaderValue, Request, Response};
+use axum::middleware::{self, AddExtension};
 use axum::routing::get;
 use axum::{Router, ServiceExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use h3::server::RequestStream;
+use http_body_util::BodyExt;
 use hyper::body::Incoming;
 use hyper::service::HttpService;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn;
 use hyper_util::server::conn::auto::UpgradeableConnection;
-use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
 use quinn::crypto::rustls::QuicServerConfig;
 use quinn::Endpoint;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::sign::CertifiedKey;
 use rustls::ServerConfig;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::future::Future;
+use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
 use tokio::select;
 use tokio::task::JoinSet;
 use tokio_rustls::TlsAcceptor;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
-use tracing::{debug, info_span, trace, trace_span, Instrument};
+use tower_http::set_header::SetResponseHeaderLayer;
+use tracing::{debug, info, info_span, trace, trace_span, warn, Instrument, Span};
+
+/// Certificates are renewed this long before they expire, a third of the
+/// lifetime of a Let's Encrypt certificate.
+const RENEW_BEFORE_EXPIRY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
+/// A certificate loaded for the HTTPS and QUIC listeners, and when to
+/// replace it with a renewed one.
 pub(crate) struct RustlsConfig {
-    tcp_config: Arc<ServerConfig>,
+    certified_key: Arc<CertifiedKey>,
     renew_after: DateTime,
 }
 
-impl TryFrom<TlsConfig> for crate::server::RustlsConfig {
+impl TryFrom<TlsConfig> for RustlsConfig {
     type Error = anyhow::Error;
 
     fn try_from(value: TlsConfig) -> std::result::Result<Self, Self::Error> {
-        let priv_key_file_path = value.tls_priv_key.context("TLS private key not provided")?;
-        let pub_cert_file_path = value
+        let leaf_cert = value
             .tls_pub_cert
-            .context("TLS public certificate not provided")?;
+            .as_ref()
+            .context("TLS public certificate not provided")?
+            .first()
+            .context("TLS public certificate chain is empty")?;
+        let renew_after = expires_at(leaf_cert)?.saturating_sub(RENEW_BEFORE_EXPIRY);
+
+        Ok(Self {
+            certified_key: certified_key(value)?,
+            renew_after,
+        })
+    }
+}
 
-        todo!()
+impl RustlsConfig {
+    /// The certificate and key, as installed into a [`CertResolver`].
+    pub(crate) fn certified_key(&self) -> Arc<CertifiedKey> {
+        self.certified_key.clone()
     }
+
+    /// When the certificate should be replaced by a renewed one.
+    pub(crate) fn renew_after(&self) -> DateTime {
+        self.renew_after
+    }
+}
+
+/// The TLS configuration for certificates from `resolver`, which may change
+/// while serving, asking for client certificates if `client_verifier` is set.
+fn resolver_server_config(
+    resolver: Arc<CertResolver>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> ServerConfig {
+    let builder = ServerConfig::builder();
+    let builder = match client_verifier {
+        Some(client_verifier) => builder.with_client_cert_verifier(client_verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(resolver);
+    // Offered in order of preference; HTTP/1.1 remains for older clients.
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    server_config
+}
+
+/// Loads a certificate and its key for use by a certificate resolver.
+pub(crate) fn certified_key(tls_config: TlsConfig) -> Result<Arc<CertifiedKey>> {
+    let priv_key_der = tls_config
+        .tls_priv_key
+        .context("TLS private key not provided")?;
+    let pub_cert_chain = tls_config
+        .tls_pub_cert
+        .context("TLS public certificate not provided")?;
+
+    let priv_key = PrivateKeyDer::try_from(priv_key_der).map_err(|err| anyhow!(err))?;
+    let cert_chain = pub_cert_chain
+        .into_iter()
+        .map(CertificateDer::from)
+        .collect();
+    let provider = ServerConfig::builder().crypto_provider().clone();
+    let signing_key = provider.key_provider.load_private_key(priv_key)?;
+
+    Ok(Arc::new(CertifiedKey::new(cert_chain, signing_key)))
 }
 
-impl RustlsConfig {}
+/// The end of the validity period of a DER encoded certificate.
+fn expires_at(cert_der: &[u8]) -> Result<DateTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|err| anyhow!("failed to parse TLS public certificate: {}", err))?;
+    let not_after_secs = cert.validity().not_after.timestamp();
+
+    Ok(DateTime::from(not_after_secs.saturating_mul(1000)))
+}
 
 struct HttpsServer {
     server_config: Arc<ServerConfig>,
-    https_listeners: Vec<TcpListener>,
+    https_listeners: Vec<Listener>,
+    /// HTTP/3 listeners, using the same certificate.
+    quic_endpoints: Vec<Endpoint>,
 }
 
+/// How long connections and background tasks get to finish on shutdown
+/// unless configured otherwise.
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
 struct Server {
-    http_listeners: Vec<TcpListener>,
+    http_listeners: Vec<Listener>,
     https_server: Option<HttpsServer>,
+    /// Reloads the certificate when it's read from files.
+    pem_watcher: Option<PemWatcher>,
+    /// Cancelled once shutdown begins, which stops the listeners.
     cancellation_token: CancellationToken,
-    server_state: ServerState,
     conn_tracker: TaskTracker,
+    /// Cancelled when connections outlive the shutdown timeout.
+    force_close_token: CancellationToken,
+    /// Tasks such as the job worker, stopped after connections have drained.
+    background_tasks: TaskTracker,
+    background_token: CancellationToken,
+    shutdown_timeout: Duration,
+    /// Whether the HTTP listeners redirect to HTTPS when both are bound.
+    https_redirect: bool,
+    /// The port in redirect URLs, if not the one HTTPS is bound to.
+    https_redirect_port: Option<u16>,
+    /// `Strict-Transport-Security` on HTTPS responses.
+    hsts: Option<HeaderValue>,
+    /// Kept reachable over plain HTTP when redirecting.
+    acme_challenges: Option<Challenges>,
+    /// Verifies optional client certificates for the admin endpoints.
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    /// Whether connections on the TCP and Unix listeners start with a PROXY
+    /// protocol header.
+    proxy_protocol: bool,
+    /// Proxies whose `X-Forwarded-For` headers are believed.
+    trusted_proxies: Vec<IpNetwork>,
+    router: Router,
 }
 
 impl Server {
-    pub(super) fn new(server_state: ServerState) -> Self {
+    pub(super) fn new() -> Self {
+        let router = Router::new().route(
+            "/",
+            get(
+                |ConnectInfo(remote_addr): ConnectInfo<SocketAddr>| async move {
+                    format!("Hello {remote_addr}")
+                },
+            ),
+        );
+
         Self {
             http_listeners: vec![],
             https_server: None,
+            pem_watcher: None,
             cancellation_token: CancellationToken::new(),
-            server_state,
             conn_tracker: TaskTracker::new(),
+            force_close_token: CancellationToken::new(),
+            background_tasks: TaskTracker::new(),
+            background_token: CancellationToken::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            https_redirect: true,
+            https_redirect_port: None,
+            hsts: hsts_header(DEFAULT_HSTS_MAX_AGE_SECS, false),
+            acme_challenges: None,
+            client_verifier: None,
+            proxy_protocol: false,
+            trusted_proxies: vec![],
+            router,
         }
     }
 
+    /// Sets how long connections and background tasks may keep running
+    /// after a shutdown signal before connections are closed.
+    pub(super) fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+
+        self
+    }
+
+    /// Runs `task` alongside the server. The token it is given is cancelled
+    /// once in-flight requests have drained, and shutdown waits for the task
+    /// to return until the shutdown timeout.
+    pub(super) fn spawn_background<F, Fut>(&self, task: F)
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.background_tasks
+            .spawn(task(self.background_token.clone()).in_current_span());
+    }
+
+    /// Asks HTTPS clients for a certificate issued by one of the CAs in
+    /// `ca_bundle`. Must come before the certificate is configured.
+    pub(super) fn client_auth(mut self, ca_bundle: Vec<Vec<u8>>) -> Result<Self> {
+        self.client_verifier = Some(client_verifier(ca_bundle)?);
+
+        Ok(self)
+    }
+
+    /// Adds `router` under [`ADMIN_PATH`], only reachable with a verified
+    /// client certificate. Its handlers can extract the [`ClientIdentity`].
+    pub(super) fn merge_admin_router(mut self, router: Router) -> Self {
+        let admin_router = Router::new()
+            .nest(ADMIN_PATH, router)
+            .layer(middleware::from_fn(require_client_identity));
+        self.router = self.router.merge(admin_router);
+
+        self
+    }
+
+    /// Adds the routes of `router`, such as the Discord interactions endpoint.
+    pub(super) fn merge_router(mut self, router: Router) -> Self {
+        self.router = self.router.merge(router);
+
+        self
+    }
+
+    /// Serves the bot's interactions and metrics endpoints, and runs its
+    /// command registration, job worker and gateway connection alongside the
+    /// server. Deferred commands get to finish once connections have drained.
+    pub(super) fn discord_bot(self, discord_bot: Arc<DiscordBotState>) -> Self {
+        let server = self.merge_router(discord_bot.clone().router());
+
+        let bot = discord_bot.clone();
+        server.spawn_background(|_| async move {
+            if let Err(err) = bot.register_commands().await {
+                warn!("failed to register commands: {:#}", err);
+            }
+        });
+        let bot = discord_bot.clone();
+        server.spawn_background(|cancellation_token| async move {
+            if let Err(err) = bot.run_gateway(cancellation_token).await {
+                warn!("gateway connection failed: {:#}", err);
+            }
+        });
+        server.spawn_background(|cancellation_token| {
+            discord_bot.clone().run_jobs(cancellation_token)
+        });
+        server
+            .spawn_background(|cancellation_token| discord_bot.finish_deferred(cancellation_token));
+
+        server
+    }
+
     pub(super) fn bind_http(mut self, socket_addr: impl ToSocketAddrs) -> Result<Self> {
         let mut listeners = bind_tcp_listeners(socket_addr)?;
         self.http_listeners.append(&mut listeners);
@@ -80,52 +303,317 @@ impl Server {
         Ok(self)
     }
 
-    pub(super) fn bind_https_tcp(mut self, socket_addr: impl ToSocketAddrs) -> Result<Self> {
-        let listeners = bind_tcp_listeners(socket_addr)?;
-        // for listener in listeners {
-        //     self.https_listeners.push(HttpsListener::TCP(listener))
-        // }
+    /// Serves plain HTTP on a Unix socket at `path`, e.g. for a reverse proxy
+    /// on the same host.
+    #[cfg(unix)]
+    pub(super) fn bind_http_unix(mut self, path: &Path) -> Result<Self> {
+        let listener = listener::bind_unix_listener(path)?;
+        self.http_listeners.push(listener);
 
         Ok(self)
     }
 
-    pub(super) async fn serve(mut self) -> Self {
-        let app = Router::new().route(
-            "/",
-            get(
-                |ConnectInfo(remote_addr): ConnectInfo<SocketAddr>| async move {
-                    format!("Hello {remote_addr}")
-                },
-            ),
+    /// Serves on the sockets passed by systemd socket activation: HTTPS on
+    /// those named [`SYSTEMD_HTTPS_NAME`](listener::SYSTEMD_HTTPS_NAME) and
+    /// plain HTTP on the rest.
+    #[cfg(unix)]
+    pub(super) fn bind_systemd(mut self) -> Result<Self> {
+        for (name, listener) in listener::take_systemd_listeners()? {
+            info!("listening on {} from systemd", listener.bind_address());
+            if name.as_deref() == Some(listener::SYSTEMD_HTTPS_NAME) {
+                self.https_server
+                    .as_mut()
+                    .context("TLS must be configured for the systemd HTTPS socket")?
+                    .https_listeners
+                    .push(listener);
+            } else {
+                self.http_listeners.push(listener);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Applies the sockets, certificate and shutdown timeout given on the
+    /// command line, and serves `discord_bot` and the admin endpoints.
+    pub(super) fn configure(
+        mut self,
+        cli_config: CliConfig,
+        database: Arc<Database>,
+        discord_bot: Arc<DiscordBotState>,
+    ) -> Result<Self> {
+        let CliConfig {
+            bind_config,
+            tls_files,
+            acme_config,
+        } = cli_config;
+
+        // Needed before the TLS configuration is built.
+        if let Some(ca_bundle) = bind_config.client_ca_bundle()? {
+            self = self.client_auth(ca_bundle)?;
+        }
+        if tls_files.is_some() {
+            self = self.tls_files(tls_files)?;
+        } else if acme_config.is_some() {
+            self = self.acme(acme_config, database.clone());
+        }
+        for socket_addr in bind_config.http_socket {
+            self = self.bind_http(socket_addr)?;
+        }
+        for socket_addr in bind_config.https_socket {
+            self = self.bind_https_tcp(socket_addr)?;
+            if bind_config.http3 {
+                self = self.bind_https_quic(socket_addr)?;
+            }
+        }
+        #[cfg(unix)]
+        {
+            for path in &bind_config.http_unix_socket {
+                self = self.bind_http_unix(path)?;
+            }
+            self = self.bind_systemd()?;
+        }
+
+        let hsts = hsts_header(
+            bind_config.hsts_max_age_secs,
+            bind_config.hsts_include_subdomains,
         );
 
+        Ok(self
+            .shutdown_timeout(Duration::from_secs(bind_config.shutdown_timeout_secs))
+            .https_redirect(
+                !bind_config.no_https_redirect,
+                bind_config.https_redirect_port,
+            )
+            .hsts(hsts)
+            .proxy_protocol(bind_config.proxy_protocol)
+            .trusted_proxies(bind_config.trusted_proxy)
+            .merge_admin_router(admin_panel::router(database))
+            .discord_bot(discord_bot))
+    }
+
+    /// Sets whether plain HTTP requests are redirected to HTTPS, and to
+    /// which port if HTTPS is reachable on a different one than it's bound to.
+    /// Only takes effect on TCP HTTP listeners, and with a TCP HTTPS listener.
+    pub(super) fn https_redirect(mut self, enabled: bool, port: Option<u16>) -> Self {
+        self.https_redirect = enabled;
+        self.https_redirect_port = port;
+
+        self
+    }
+
+    /// Sets whether connections on the TCP and Unix listeners start with a
+    /// PROXY protocol header, as sent by load balancers. Only enable it when
+    /// the listeners are reachable through the load balancer alone, since
+    /// anyone else could claim any address.
+    pub(super) fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+
+        self
+    }
+
+    /// Takes the client's address from `X-Forwarded-For` on requests from
+    /// `trusted_proxies`.
+    pub(super) fn trusted_proxies(mut self, trusted_proxies: Vec<IpNetwork>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+
+        self
+    }
+
+    /// Sets the `Strict-Transport-Security` header of HTTPS responses, or
+    /// leaves it out with `None`.
+    pub(super) fn hsts(mut self, hsts: Option<HeaderValue>) -> Self {
+        self.hsts = hsts;
+
+        self
+    }
+
+    /// Serves the certificate from PEM files, reloading it whenever they
+    /// change without restarting the listeners.
+    pub(super) fn tls_files(mut self, tls_files: TlsFiles) -> Result<Self> {
+        let resolver = Arc::new(CertResolver::new(None, None));
+        let mut pem_watcher = PemWatcher::new(tls_files, resolver.clone());
+        pem_watcher.reload_if_changed()?;
+        self.pem_watcher = Some(pem_watcher);
+
+        let server_config = resolver_server_config(resolver, self.client_verifier.clone());
+
+        Ok(self.server_config(Arc::new(server_config)))
+    }
+
+    /// Obtains the certificate from an ACME CA and keeps it renewed in the
+    /// background. HTTPS handshakes fail until the first one is issued.
+    pub(super) fn acme(self, acme_config: AcmeConfig, database: Arc<Database>) -> Self {
+        let challenges = Challenges::default();
+        let is_tls_alpn = acme_config.acme_challenge == AcmeChallenge::TlsAlpn01;
+        let resolver = Arc::new(CertResolver::new(
+            None,
+            is_tls_alpn.then(|| challenges.clone()),
+        ));
+        let mut server_config =
+            resolver_server_config(resolver.clone(), self.client_verifier.clone());
+        if is_tls_alpn {
+            server_config.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+        }
+        let manager = CertificateManager::new(acme_config, database, resolver, challenges.clone());
+
+        let mut server = self
+            .merge_router(challenges.router())
+            .server_config(Arc::new(server_config));
+        server.acme_challenges = Some(challenges);
+        server.spawn_background(|cancellation_token| manager.run(cancellation_token));
+
+        server
+    }
+
+    fn server_config(mut self, server_config: Arc<ServerConfig>) -> Self {
+        let (https_listeners, quic_endpoints) = self
+            .https_server
+            .take()
+            .map(|https_server| (https_server.https_listeners, https_server.quic_endpoints))
+            .unwrap_or_default();
+        self.https_server = Some(HttpsServer {
+            server_config,
+            https_listeners,
+            quic_endpoints,
+        });
+
+        self
+    }
+
+    pub(super) fn bind_https_tcp(mut self, socket_addr: impl ToSocketAddrs) -> Result<Self> {
+        let https_server = self
+            .https_server
+            .as_mut()
+            .context("TLS must be configured before binding an HTTPS socket")?;
+        let mut listeners = bind_tcp_listeners(socket_addr)?;
+        https_server.https_listeners.append(&mut listeners);
+
+        Ok(self)
+    }
+
+    /// Serves HTTP/3 on the UDP port of `socket_addr`, advertised to clients
+    /// of the TCP listeners with an `Alt-Svc` header.
+    pub(super) fn bind_https_quic(mut self, socket_addr: impl ToSocketAddrs) -> Result<Self> {
+        let https_server = self
+            .https_server
+            .as_mut()
+            .context("TLS must be configured before binding an HTTP/3 socket")?;
+
+        let mut tls_config = (*https_server.server_config).clone();
+        tls_config.alpn_protocols = vec![b"h3".to_vec()];
+        let quic_config = QuicServerConfig::try_from(tls_config)
+            .context("TLS configuration can't be used for QUIC")?;
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_config));
+
+        for socket_addr in socket_addr.to_socket_addrs()? {
+            let endpoint = Endpoint::server(server_config.clone(), socket_addr)
+                .context("unable to bind UDP socket")?;
+            https_server.quic_endpoints.push(endpoint);
+        }
+
+        Ok(self)
+    }
+
+    /// The port plain HTTP requests are redirected to, or `None` if they're
+    /// served as they are.
+    fn https_redirect_target(&self) -> Option<u16> {
+        if !self.https_redirect || !self.http_listeners.iter().any(|l| l.port().is_some()) {
+            return None;
+        }
+        let https_port = self
+            .https_server
+            .as_ref()?
+            .https_listeners
+            .iter()
+            .find_map(Listener::port)?;
+
+        Some(self.https_redirect_port.unwrap_or(https_port))
+    }
+
+    /// Serves until SIGINT, SIGTERM or cancellation, then stops accepting,
+    /// lets in-flight requests and background tasks finish until the
+    /// shutdown timeout and closes whatever is left.
+    pub(super) async fn serve(mut self) {
+        let mut app = std::mem::take(&mut self.router);
+        if !self.trusted_proxies.is_empty() {
+            let trusted_proxies: Arc<[IpNetwork]> =
+                std::mem::take(&mut self.trusted_proxies).into();
+            app = app.layer(middleware::from_fn_with_state(
+                trusted_proxies,
+                resolve_forwarded_client,
+            ));
+        }
+
+        // Unix sockets sit behind a proxy that already terminated TLS, so
+        // only TCP listeners redirect.
+        let redirect_app = self
+            .https_redirect_target()
+            .map(|https_port| https_redirect_router(https_port, self.acme_challenges.as_ref()));
+        let http_app = app.clone();
+        // Browsers ignore HSTS over plain HTTP, so it's only added for HTTPS.
+        if let Some(hsts) = self.hsts.clone() {
+            app = app.layer(SetResponseHeaderLayer::if_not_present(
+                header::STRICT_TRANSPORT_SECURITY,
+                hsts,
+            ));
+        }
+        // HTTP/3 is only reachable with TLS, so neither is Alt-Svc.
+        if let Some(alt_svc) = self.https_server.as_ref().and_then(alt_svc_header) {
+            app = app.layer(SetResponseHeaderLayer::if_not_present(
+                header::ALT_SVC,
+                alt_svc,
+            ));
+        }
+
         let conn_graceful_shutdown = Arc::new(GracefulShutdown::new());
         let mut http_joinset = JoinSet::new();
 
+        let http_make_service = http_app.into_make_service_with_connect_info::<SocketAddr>();
+        let redirect_make_service =
+            redirect_app.map(|app| app.into_make_service_with_connect_info::<SocketAddr>());
         let mut make_service = app.into_make_service_with_connect_info::<SocketAddr>();
 
         while let Some(listener) = self.http_listeners.pop() {
-            let mut service = make_service.clone();
+            let service = match &redirect_make_service {
+                Some(redirect_make_service) if listener.port().is_some() => {
+                    redirect_make_service.clone()
+                }
+                _ => http_make_service.clone(),
+            };
             let shutdown_token = self.cancellation_token.child_token();
-            let server_state = self.server_state.clone();
-            let bind_address = listener
-                .local_addr()
-                .map(|s| s.to_string())
-                .unwrap_or_else(|_| "unknown address".to_string());
+            let bind_address = listener.bind_address();
 
             let listener_span = trace_span!("HTTP listener", bind_address);
+            let conn_tracker = self.conn_tracker.clone();
+            let force_close_token = self.force_close_token.clone();
+            let conn_graceful_shutdown = conn_graceful_shutdown.clone();
+            let proxy_protocol = self.proxy_protocol;
 
             http_joinset.spawn(
                 async move {
-                    loop {
-                        match listener.accept().await {
-                            Ok((stream, remote_addr)) => {}
-                            Err(err) => {
-                                todo!()
-                            }
-                        }
+                    while let Some((mut stream, remote_addr)) =
+                        accept_connection(&listener, &shutdown_token).await
+                    {
+                        let conn_span = info_span!("HTTP", "remote" = remote_addr.to_string());
+                        let mut service = service.clone();
+                        let watcher = conn_graceful_shutdown.watcher();
+
+                        let connection = async move {
+                            let Some(remote_addr) =
+                                client_addr(&mut stream, remote_addr, proxy_protocol).await
+                            else {
+                                return;
+                            };
+                            // Infallible
+                            use tower::Service;
+                            let tower_service = service.call(remote_addr).await.unwrap();
+
+                            handle_tcp_stream(stream, tower_service, watcher, None).await;
+                        };
+                        spawn_connection(&conn_tracker, &force_close_token, connection, conn_span);
                     }
-                    return ();
+                    trace!("stopped accepting connections");
                 }
                 .instrument(listener_span),
             );
@@ -134,30 +622,43 @@ impl Server {
         let https_joinset_opt = if let Some(https_server) = self.https_server {
             let mut https_joinset = JoinSet::new();
             let cert_refresh_token = self.cancellation_token.child_token();
+            if let Some(pem_watcher) = self.pem_watcher.take() {
+                https_joinset.spawn(pem_watcher.run(cert_refresh_token).in_current_span());
+            }
             let tls_acceptor = TlsAcceptor::from(https_server.server_config);
 
             for listener in https_server.https_listeners {
                 let conn_tracker = self.conn_tracker.clone();
-                let mut service = make_service.clone();
-                let local_addr = listener
-                    .local_addr()
-                    .map(|s| s.to_string())
-                    .unwrap_or_else(|_| "unknown address".to_string());
+                let service = make_service.clone();
+                let local_addr = listener.bind_address();
                 let listener_span = trace_span!("HTTP listener", local_addr);
                 let tls_acceptor = tls_acceptor.clone();
+                let shutdown_token = self.cancellation_token.child_token();
+                let force_close_token = self.force_close_token.clone();
+                let conn_graceful_shutdown = conn_graceful_shutdown.clone();
+                let proxy_protocol = self.proxy_protocol;
 
                 https_joinset.spawn(async move {
-                    loop {
-                        // TODO: Remove unwrap
-                        let (tcp_stream, remote_addr) = listener.accept().await.unwrap();
+                    while let Some((mut stream, remote_addr)) =
+                        accept_connection(&listener, &shutdown_token).await
+                    {
                         let conn_span = info_span!("HTTPS", "remote"=remote_addr.to_string());
                         let tls_acceptor = tls_acceptor.clone();
-                        // Infallible
-                        use tower::Service;
-                        let tower_service = service.call(remote_addr).await.unwrap();
+                        let mut service = service.clone();
 
-                        conn_tracker.spawn(async move {
-                            let tls_stream = match tls_acceptor.accept(tcp_stream).await {
+                        let watcher = conn_graceful_shutdown.watcher();
+                        let connection = async move {
+                            // The PROXY protocol header comes before the TLS handshake.
+                            let Some(remote_addr) =
+                                client_addr(&mut stream, remote_addr, proxy_protocol).await
+                            else {
+                                return;
+                            };
+                            // Infallible
+                            use tower::Service;
+                            let tower_service = service.call(remote_addr).await.unwrap();
+
+                            let tls_stream = match tls_acceptor.accept(stream).await {
                                 Ok(stream) => stream,
                                 Err(err) => {
                                     debug!("failed to perform TLS handshake, ending the connection");
@@ -166,44 +667,384 @@ impl Server {
                                 }
                             };
 
-                            handle_tcp_stream(tls_stream, tower_service).await;
-                        }.instrument(conn_span));
+                            let client_identity = ClientIdentity::from_peer_certificates(
+                                tls_stream.get_ref().1.peer_certificates(),
+                            );
+
+                            handle_tcp_stream(tls_stream, tower_service, watcher, client_identity)
+                                .await;
+                        };
+                        spawn_connection(&conn_tracker, &force_close_token, connection, conn_span);
                     }
-                    return ()
+                    trace!("stopped accepting connections");
                 }.instrument(listener_span));
             }
 
+            for endpoint in https_server.quic_endpoints {
+                let conn_tracker = self.conn_tracker.clone();
+                let mut service = make_service.clone();
+                let local_addr = endpoint
+                    .local_addr()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|_| "unknown address".to_string());
+                let listener_span = trace_span!("HTTP/3 listener", local_addr);
+                let shutdown_token = self.cancellation_token.child_token();
+                let force_close_token = self.force_close_token.clone();
+
+                https_joinset.spawn(
+                    async move {
+                        loop {
+                            let incoming = select! {
+                                _ = shutdown_token.cancelled() => break,
+                                incoming = endpoint.accept() => incoming,
+                            };
+                            let Some(incoming) = incoming else {
+                                break;
+                            };
+                            let remote_addr = incoming.remote_address();
+                            let conn_span =
+                                info_span!("HTTP/3", "remote" = remote_addr.to_string());
+                            // Infallible
+                            use tower::Service;
+                            let tower_service = service.call(remote_addr).await.unwrap();
+
+                            let connection = handle_quic_connection(
+                                incoming,
+                                tower_service,
+                                shutdown_token.clone(),
+                            );
+                            spawn_connection(
+                                &conn_tracker,
+                                &force_close_token,
+                                connection,
+                                conn_span,
+                            );
+                        }
+                        // Connections keep the endpoint running until they end.
+                        trace!("stopped accepting connections");
+                    }
+                    .instrument(listener_span),
+                );
+            }
+
             Some(https_joinset)
         } else {
             None
         };
 
-        http_joinset.abort_all();
-        if let Some(https_joinset) = https_joinset_opt {}
-        match Arc::into_inner(conn_graceful_shutdown) {
-            None => todo!(),
-            Some(_) => todo!(),
+        select! {
+            _ = shutdown_signal() => info!("received shutdown signal"),
+            _ = self.cancellation_token.cancelled() => {}
+        }
+        self.cancellation_token.cancel();
+
+        // Listeners return once the cancellation token is cancelled.
+        while http_joinset.join_next().await.is_some() {}
+        if let Some(mut https_joinset) = https_joinset_opt {
+            while https_joinset.join_next().await.is_some() {}
+        }
+        debug!("stopped listening, draining connections");
+
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            // The listeners held the only other references.
+            match Arc::into_inner(conn_graceful_shutdown) {
+                Some(graceful_shutdown) => graceful_shutdown.shutdown().await,
+                None => debug!("graceful shutdown still shared, waiting on connections only"),
+            }
+            self.conn_tracker.close();
+            self.conn_tracker.wait().await;
+
+            // Deferred commands and jobs started by the drained requests
+            // still get to finish.
+            self.background_token.cancel();
+            self.background_tasks.close();
+            self.background_tasks.wait().await;
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                "connections or tasks still running after {:?}, closing them",
+                self.shutdown_timeout
+            );
+            self.background_token.cancel();
+            self.force_close_token.cancel();
+            self.conn_tracker.close();
+            self.conn_tracker.wait().await;
+        }
+        info!("server stopped");
+    }
+}
+
+/// How long clients may keep using the advertised HTTP/3 endpoint, in seconds.
+const ALT_SVC_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// `Alt-Svc` advertising the HTTP/3 ports, or `None` without HTTP/3 listeners.
+fn alt_svc_header(https_server: &HttpsServer) -> Option<HeaderValue> {
+    let mut ports: Vec<u16> = https_server
+        .quic_endpoints
+        .iter()
+        .filter_map(|endpoint| endpoint.local_addr().ok())
+        .map(|local_addr| local_addr.port())
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+    if ports.is_empty() {
+        return None;
+    }
+
+    let alternatives: Vec<String> = ports
+        .into_iter()
+        .map(|port| format!("h3=\":{}\"; ma={}", port, ALT_SVC_MAX_AGE_SECS))
+        .collect();
+
+    HeaderValue::from_str(&alternatives.join(", ")).ok()
+}
+
+/// Resolves on SIGINT, or on SIGTERM as sent by container runtimes.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("failed to listen for SIGINT: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// Runs `connection` on `conn_tracker` until it ends or `force_close_token`
+/// is cancelled, whichever comes first.
+fn spawn_connection(
+    conn_tracker: &TaskTracker,
+    force_close_token: &CancellationToken,
+    connection: impl Future<Output = ()> + Send + 'static,
+    conn_span: Span,
+) {
+    let force_close_token = force_close_token.clone();
+
+    conn_tracker.spawn(
+        async move {
+            select! {
+                _ = connection => {}
+                _ = force_close_token.cancelled() => {
+                    debug!("closing connection still open after the shutdown timeout");
+                }
+            }
+        }
+        .instrument(conn_span),
+    );
+}
+
+/// How long to stop accepting after running out of file descriptors or
+/// memory, giving open connections a chance to finish and free them.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accepts the next connection, or returns `None` once `shutdown_token` is
+/// cancelled. Accept errors are logged and never end the listener.
+async fn accept_connection(
+    listener: &Listener,
+    shutdown_token: &CancellationToken,
+) -> Option<(Stream, SocketAddr)> {
+    loop {
+        let err = select! {
+            _ = shutdown_token.cancelled() => return None,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => return Some(accepted),
+                Err(err) => err,
+            },
+        };
+
+        // The peer gave up before we accepted; nothing to wait out.
+        if is_connection_error(&err) {
+            trace!("connection failed before it was accepted: {}", err);
+            continue;
+        }
+
+        // EMFILE, ENFILE, ENOMEM and the like. Accepting again right away
+        // would spin, so wait for connections to close first.
+        warn!(
+            "failed to accept a connection, retrying in {:?}: {}",
+            ACCEPT_ERROR_BACKOFF, err
+        );
+        select! {
+            _ = shutdown_token.cancelled() => return None,
+            _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => {}
+        }
+    }
+}
+
+/// The client's address: `remote_addr`, or the one in the PROXY protocol
+/// header if enabled, which is then also recorded on the connection span.
+/// `None` if the header is missing or invalid.
+async fn client_addr(
+    stream: &mut Stream,
+    remote_addr: SocketAddr,
+    proxy_protocol: bool,
+) -> Option<SocketAddr> {
+    if !proxy_protocol {
+        return Some(remote_addr);
+    }
+
+    match read_proxy_header(stream, remote_addr).await {
+        Ok(client_addr) => {
+            Span::current().record("remote", client_addr.to_string());
+            trace!("connection proxied by {}", remote_addr);
+            Some(client_addr)
+        }
+        Err(err) => {
+            debug!(
+                "failed to read PROXY protocol header, ending the connection: {:#}",
+                err
+            );
+            None
+        }
+    }
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Serves the HTTP/3 requests of a QUIC connection until the client closes
+/// it. Once `shutdown_token` is cancelled, the client is told not to send
+/// new requests and the ones in flight are finished.
+async fn handle_quic_connection(
+    incoming: quinn::Incoming,
+    service: AddExtension<Router, ConnectInfo<SocketAddr>>,
+    shutdown_token: CancellationToken,
+) {
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            debug!("failed to perform QUIC handshake, ending the connection");
+            trace!("error thrown: {}", err);
+            return;
+        }
+    };
+    let client_identity = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certificates| {
+            ClientIdentity::from_peer_certificates(Some(certificates.as_slice()))
+        });
+    let mut h3_connection =
+        match h3::server::Connection::new(h3_quinn::Connection::new(connection)).await {
+            Ok(h3_connection) => h3_connection,
+            Err(err) => {
+                debug!("failed to set up HTTP/3 connection: {}", err);
+                return;
+            }
+        };
+
+    // Requests are aborted along with the connection if it's force closed.
+    let mut requests = JoinSet::new();
+    let mut shutting_down = false;
+    loop {
+        let accepted = select! {
+            accepted = h3_connection.accept() => Some(accepted),
+            _ = shutdown_token.cancelled(), if !shutting_down => None,
+        };
+
+        match accepted {
+            Some(Ok(Some((mut request, stream)))) => {
+                if let Some(client_identity) = &client_identity {
+                    request.extensions_mut().insert(client_identity.clone());
+                }
+                let service = service.clone();
+                requests.spawn(
+                    async move {
+                        if let Err(err) = serve_h3_request(request, stream, service).await {
+                            debug!("failed to serve HTTP/3 request: {:#}", err);
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+            Some(Ok(None)) => break,
+            Some(Err(err)) => {
+                debug!("HTTP/3 connection ended with an error: {}", err);
+                break;
+            }
+            None => {
+                shutting_down = true;
+                if let Err(err) = h3_connection.shutdown(0).await {
+                    debug!("failed to shut down HTTP/3 connection: {}", err);
+                    break;
+                }
+            }
         }
     }
+
+    while requests.join_next().await.is_some() {}
+    trace!("HTTP/3 connection ended");
 }
 
-fn bind_tcp_listeners(socket_addrs: impl ToSocketAddrs) -> Result<Vec<TcpListener>> {
-    let mut listeners = vec![];
+/// Passes an HTTP/3 request to the router. The request body is read in full
+/// first, which is fine for the small payloads this server receives.
+async fn serve_h3_request(
+    request: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    service: AddExtension<Router, ConnectInfo<SocketAddr>>,
+) -> Result<()> {
+    use tower::ServiceExt;
 
-    for socket_addr in socket_addrs.to_socket_addrs()? {
-        let std_listener = std::net::TcpListener::bind(socket_addr)?;
-        std_listener.set_nonblocking(true)?;
-        let listener = TcpListener::from_std(std_listener).context("unable to bind socket")?;
+    let mut request_body = BytesMut::new();
+    while let Some(chunk) = stream.recv_data().await? {
+        request_body.put(chunk);
+    }
+    let request = request.map(|()| Body::from(request_body.freeze()));
 
-        listeners.push(listener);
+    let response = match service.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    };
+    let (parts, mut response_body) = response.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+
+    while let Some(frame) = response_body.frame().await {
+        // Trailers aren't used by any route.
+        if let Ok(data) = frame?.into_data() {
+            stream.send_data(data).await?;
+        }
     }
+    stream.finish().await?;
 
-    Ok(listeners)
+    Ok(())
 }
 
 async fn handle_tcp_stream<Stream: AsyncWrite + AsyncRead + Send + Unpin + 'static>(
     stream: Stream,
     service: AddExtension<Router, ConnectInfo<SocketAddr>>,
+    watcher: Watcher,
+    client_identity: Option<ClientIdentity>,
 ) {
     use tower::util::Oneshot;
     use tower::ServiceExt;
@@ -213,7 +1054,10 @@ async fn handle_tcp_stream<Stream: AsyncWrite + AsyncRead + Send + Unpin + 'stat
     // Hyper also has its own `Service` trait and doesn't use tower. We can use
     // `hyper::service::service_fn` to create a hyper `Service` that calls our app through
     // `tower::Service::call`.
-    let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
+    let hyper_service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        if let Some(client_identity) = &client_identity {
+            request.extensions_mut().insert(client_identity.clone());
+        }
         // We have to clone `tower_service` because hyper's `Service` uses `&self` whereas
         // tower's `Service` requires `&mut self`.
         //
@@ -221,11 +1065,312 @@ async fn handle_tcp_stream<Stream: AsyncWrite + AsyncRead + Send + Unpin + 'stat
         service.clone().oneshot(request)
     });
 
-    match conn::auto::Builder::new(TokioExecutor::new())
-        .serve_connection_with_upgrades(tokio_stream, hyper_service)
-        .await
-    {
+    let builder = conn::auto::Builder::new(TokioExecutor::new());
+    // Once shutdown begins, idle keep-alive connections are closed and busy
+    // ones after their current request.
+    let connection = builder.serve_connection_with_upgrades(tokio_stream, hyper_service);
+
+    match watcher.watch(connection).await {
         Ok(()) => trace!("HTTP connection gracefully ended"),
-        Err(err) => {}
+        Err(err) => debug!("HTTP connection ended with an error: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{TlsConfig, TlsFiles};
+    use crate::server::cert_resolver::CertResolver;
+    use crate::server::client_auth::ClientIdentity;
+    use crate::server::{resolver_server_config, RustlsConfig, Server, RENEW_BEFORE_EXPIRY};
+    use crate::types::util::DateTime;
+    use axum::routing::get;
+    use axum::{Extension, Router};
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{ClientConfig, RootCertStore};
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::Notify;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::TlsConnector;
+
+    /// A self-signed certificate for `localhost` in PEM files, and the
+    /// certificate for clients to trust.
+    fn self_signed_files() -> (TlsFiles, CertificateDer<'static>) {
+        let dir = std::env::temp_dir().join(format!("genny-tls-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(dir.join("key.pem"), certified_key.key_pair.serialize_pem()).unwrap();
+        std::fs::write(dir.join("cert.pem"), certified_key.cert.pem()).unwrap();
+
+        let tls_files = TlsFiles {
+            tls_priv_key: Some(dir.join("key.pem")),
+            tls_pub_cert: Some(dir.join("cert.pem")),
+        };
+
+        (tls_files, certified_key.cert.der().clone())
+    }
+
+    fn https_port(server: &Server) -> u16 {
+        server.https_server.as_ref().unwrap().https_listeners[0]
+            .port()
+            .unwrap()
+    }
+
+    async fn tls_connect(
+        port: u16,
+        server_cert: &CertificateDer<'static>,
+        client_cert: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> TlsStream<TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots.add(server_cert.clone()).unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let client_config = match client_cert {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    /// Sends `request` and reads the response until the connection closes.
+    async fn send_request<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
+        request: &str,
+    ) -> String {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        // TLS connections may end without close_notify once the response is complete.
+        let _ = stream.read_to_end(&mut response).await;
+
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn server_config_offers_h2() {
+        let server_config = resolver_server_config(Arc::new(CertResolver::new(None, None)), None);
+
+        assert_eq!(
+            server_config.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+    }
+
+    #[test]
+    fn rustls_config_renews_before_expiry() {
+        let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let tls_config = TlsConfig {
+            tls_priv_key: Some(certified_key.key_pair.serialize_der()),
+            tls_pub_cert: Some(vec![certified_key.cert.der().to_vec()]),
+        };
+
+        // rcgen certificates are valid until 4096.
+        let rustls_config = RustlsConfig::try_from(tls_config).unwrap();
+        assert!(rustls_config.renew_after() > DateTime::now().saturating_add(RENEW_BEFORE_EXPIRY));
+    }
+
+    #[test]
+    fn rejects_missing_certificate() {
+        let tls_config = TlsConfig {
+            tls_priv_key: None,
+            tls_pub_cert: None,
+        };
+
+        assert!(RustlsConfig::try_from(tls_config).is_err());
+    }
+
+    #[cfg(feature = "rdb")]
+    #[tokio::test]
+    async fn serves_discord_interactions() {
+        use crate::discord_bot::testing::{ping_interaction, TestBot};
+
+        let bot = TestBot::start().await.unwrap();
+        let server = Server::new()
+            .bind_http("127.0.0.1:0")
+            .unwrap()
+            .discord_bot(bot.state.clone());
+        let port = server.http_listeners[0].port().unwrap();
+        let cancellation_token = server.cancellation_token.clone();
+        let serving = tokio::spawn(server.serve());
+
+        let body = serde_json::to_vec(&ping_interaction(1)).unwrap();
+        let headers = bot.signer.sign(&body, DateTime::now().timestamp_secs());
+        let mut request = format!(
+            "POST /interactions HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            body.len()
+        );
+        for (name, value) in &headers {
+            request += &format!("{}: {}\r\n", name, value.to_str().unwrap());
+        }
+        request += "\r\n";
+        request += std::str::from_utf8(&body).unwrap();
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let response = send_request(stream, &request).await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let (_, response_body) = response.split_once("\r\n\r\n").unwrap();
+        let response_body: serde_json::Value = serde_json::from_str(response_body).unwrap();
+        assert_eq!(response_body["type"], 1);
+
+        cancellation_token.cancel();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_a_client_certificate_from_the_ca() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "genny test CA");
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec![]).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "ops");
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client_cert = client_params
+            .signed_by(&client_key, &ca_cert, &ca_key)
+            .unwrap();
+
+        let (tls_files, server_cert) = self_signed_files();
+        let admin_router = Router::new().route(
+            "/whoami",
+            get(
+                |Extension(client_identity): Extension<ClientIdentity>| async move {
+                    client_identity.subject
+                },
+            ),
+        );
+        let server = Server::new()
+            .client_auth(vec![ca_cert.der().to_vec()])
+            .unwrap()
+            .tls_files(tls_files)
+            .unwrap()
+            .bind_https_tcp("127.0.0.1:0")
+            .unwrap()
+            .merge_admin_router(admin_router);
+        let port = https_port(&server);
+        let cancellation_token = server.cancellation_token.clone();
+        let serving = tokio::spawn(server.serve());
+
+        let request = "GET /admin/whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let client_auth = (
+            client_cert.der().clone(),
+            PrivateKeyDer::try_from(client_key.serialize_der()).unwrap(),
+        );
+        let stream = tls_connect(port, &server_cert, Some(client_auth)).await;
+        let response = send_request(stream, request).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("CN=ops"), "{}", response);
+
+        let stream = tls_connect(port, &server_cert, None).await;
+        let response = send_request(stream, request).await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+        cancellation_token.cancel();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_requests_and_background_tasks() {
+        let started = Arc::new(Notify::new());
+        let handler_started = started.clone();
+        let router = Router::new().route(
+            "/slow",
+            get(move || {
+                let started = handler_started.clone();
+                async move {
+                    started.notify_one();
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    "done"
+                }
+            }),
+        );
+        let server = Server::new()
+            .merge_router(router)
+            .bind_http("127.0.0.1:0")
+            .unwrap();
+        let port = server.http_listeners[0].port().unwrap();
+        let cancellation_token = server.cancellation_token.clone();
+        let background_finished = Arc::new(AtomicBool::new(false));
+        let finished = background_finished.clone();
+        server.spawn_background(|cancellation_token| async move {
+            cancellation_token.cancelled().await;
+            // Cleaning up after cancellation still gets to finish.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            finished.store(true, Ordering::SeqCst);
+        });
+        let serving = tokio::spawn(server.serve());
+
+        let request = tokio::spawn(async move {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            send_request(
+                stream,
+                "GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+        });
+        started.notified().await;
+        cancellation_token.cancel();
+        serving.await.unwrap();
+
+        assert!(background_finished.load(Ordering::SeqCst));
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+        // The listener is closed as well.
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn alt_svc_is_only_sent_over_https() {
+        let (tls_files, server_cert) = self_signed_files();
+        let server = Server::new()
+            .tls_files(tls_files)
+            .unwrap()
+            .bind_https_tcp("127.0.0.1:0")
+            .unwrap()
+            .bind_https_quic("127.0.0.1:0")
+            .unwrap()
+            .bind_http("127.0.0.1:0")
+            .unwrap()
+            .https_redirect(false, None);
+        let http_port = server.http_listeners[0].port().unwrap();
+        let https_port = https_port(&server);
+        let quic_port = server.https_server.as_ref().unwrap().quic_endpoints[0]
+            .local_addr()
+            .unwrap()
+            .port();
+        let cancellation_token = server.cancellation_token.clone();
+        let serving = tokio::spawn(server.serve());
+
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let stream = tls_connect(https_port, &server_cert, None).await;
+        let response = send_request(stream, request).await.to_lowercase();
+        assert!(response.starts_with("http/1.1 200"), "{}", response);
+        let alt_svc = format!("alt-svc: h3=\":{}\"", quic_port);
+        assert!(response.contains(&alt_svc), "{}", response);
+
+        let stream = TcpStream::connect(("127.0.0.1", http_port)).await.unwrap();
+        let response = send_request(stream, request).await.to_lowercase();
+        assert!(response.starts_with("http/1.1 200"), "{}", response);
+        assert!(!response.contains("alt-svc"), "{}", response);
+
+        cancellation_token.cancel();
+        serving.await.unwrap();
     }
 }
@@ -0,0 +1,570 @@
+//! Obtains and renews the HTTPS certificate from an ACME CA such as Let's Encrypt.
+//!
+//! The CA's challenges are answered by this server itself: HTTP-01 through a
+//! route on the HTTP listeners, TLS-ALPN-01 through [`CertResolver`] during
+//! the handshake on the HTTPS ones. The account and the issued certificate
+//! are stored in the database, so restarts reuse them instead of placing new
+//! orders.
+
+use crate::config::{AcmeChallenge, AcmeConfig, TlsConfig};
+use crate::database::{Database, DbModel};
+use crate::server::cert_resolver::CertResolver;
+use crate::server::{certified_key, RustlsConfig};
+use crate::types::server::{AcmeAccount, TlsCert};
+use crate::types::util::DateTime;
+use crate::Result;
+use anyhow::{anyhow, bail, Context};
+use axum::extract::State;
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, KeyAuthorization,
+    NewAccount, NewOrder, Order, OrderStatus,
+};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName, KeyPair};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// The ALPN protocol the CA offers when validating TLS-ALPN-01 (RFC 8737).
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+/// Where the CA fetches HTTP-01 key authorizations from.
+pub(crate) const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge";
+/// How often a pending order is checked on.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: u32 = 30;
+/// How long to wait before trying again after issuance failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+impl AcmeChallenge {
+    fn challenge_type(self) -> ChallengeType {
+        match self {
+            AcmeChallenge::Http01 => ChallengeType::Http01,
+            AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChallengeState {
+    /// HTTP-01 key authorizations by token.
+    http: HashMap<String, String>,
+    /// TLS-ALPN-01 certificates by domain.
+    tls_alpn: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// The challenges of the order in progress, shared with the listeners
+/// answering them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Challenges {
+    state: Arc<Mutex<ChallengeState>>,
+}
+
+impl Challenges {
+    /// Serves the HTTP-01 key authorizations under [`HTTP_CHALLENGE_PATH`].
+    pub(crate) fn router(&self) -> Router {
+        Router::new().nest_service(
+            HTTP_CHALLENGE_PATH,
+            get(handle_http_challenge).with_state(self.clone()),
+        )
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ChallengeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn add(
+        &self,
+        challenge: AcmeChallenge,
+        domain: &str,
+        token: &str,
+        key_authorization: &KeyAuthorization,
+    ) -> Result<()> {
+        match challenge {
+            AcmeChallenge::Http01 => {
+                self.lock()
+                    .http
+                    .insert(token.to_string(), key_authorization.as_str().to_string());
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let certificate =
+                    tls_alpn_certificate(domain, key_authorization.digest().as_ref())?;
+                self.lock().tls_alpn.insert(domain.to_string(), certificate);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn tls_alpn_certificate(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.lock().tls_alpn.get(domain).cloned()
+    }
+
+    fn clear(&self) {
+        let mut state = self.lock();
+        state.http.clear();
+        state.tls_alpn.clear();
+    }
+}
+
+async fn handle_http_challenge(State(challenges): State<Challenges>, uri: Uri) -> Response {
+    // The router is nested, so only the token is left of the path.
+    let token = uri.path().trim_start_matches('/');
+
+    match challenges.lock().http.get(token) {
+        Some(key_authorization) => key_authorization.clone().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// A self-signed certificate carrying the SHA-256 digest of the key
+/// authorization, as the CA expects it for TLS-ALPN-01.
+fn tls_alpn_certificate(domain: &str, digest: &[u8]) -> Result<Arc<CertifiedKey>> {
+    let key_pair = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let certificate = params.self_signed(&key_pair)?;
+
+    certified_key(TlsConfig {
+        tls_priv_key: Some(key_pair.serialize_der()),
+        tls_pub_cert: Some(vec![certificate.der().to_vec()]),
+    })
+}
+
+/// Keeps a valid certificate from the CA installed in a [`CertResolver`].
+pub(crate) struct CertificateManager {
+    config: AcmeConfig,
+    database: Arc<Database>,
+    resolver: Arc<CertResolver>,
+    challenges: Challenges,
+}
+
+impl CertificateManager {
+    pub(crate) fn new(
+        config: AcmeConfig,
+        database: Arc<Database>,
+        resolver: Arc<CertResolver>,
+        challenges: Challenges,
+    ) -> Self {
+        Self {
+            config,
+            database,
+            resolver,
+            challenges,
+        }
+    }
+
+    /// Installs the certificate and renews it when due, until cancelled.
+    pub(crate) async fn run(self, cancellation_token: CancellationToken) {
+        loop {
+            let wait = match self.ensure_certificate().await {
+                Ok(renew_at) => renew_at.duration_since(&DateTime::now()),
+                Err(err) => {
+                    warn!(
+                        "failed to obtain TLS certificate, retrying in {:?}: {:#}",
+                        RETRY_INTERVAL, err
+                    );
+                    RETRY_INTERVAL
+                }
+            };
+
+            select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
+    /// Installs the stored certificate, and a newly issued one if it's
+    /// missing or due for renewal. Returns when to renew next.
+    pub(crate) async fn ensure_certificate(&self) -> Result<DateTime> {
+        let key = TlsCert::key_for(&self.config.acme_domains);
+        let stored = TlsCert::get(&self.database.start_trx()?, &key).await?;
+
+        if let Some(tls_cert) = &stored {
+            // Still served while renewing, as it's most likely valid for weeks.
+            let rustls_config = RustlsConfig::try_from(tls_cert.tls_config()?)?;
+            self.resolver.set_certificate(rustls_config.certified_key());
+            if tls_cert.renew_at > DateTime::now() {
+                return Ok(tls_cert.renew_at);
+            }
+        }
+
+        let tls_cert = self.issue().await?;
+        self.database
+            .transact(async |trx| tls_cert.put(trx))
+            .await?;
+        info!(
+            "obtained TLS certificate for {}",
+            self.config.acme_domains.join(", ")
+        );
+
+        let rustls_config = RustlsConfig::try_from(tls_cert.tls_config()?)?;
+        self.resolver.set_certificate(rustls_config.certified_key());
+
+        Ok(tls_cert.renew_at)
+    }
+
+    /// Loads the account for the directory, creating it on first use.
+    async fn account(&self) -> Result<Account> {
+        let directory_url = &self.config.acme_directory_url;
+        let key = directory_url.as_bytes().to_vec();
+
+        if let Some(stored) = AcmeAccount::get(&self.database.start_trx()?, &key).await? {
+            let credentials: AccountCredentials = serde_json::from_str(&stored.credentials)?;
+            return Ok(Account::from_credentials(credentials).await?);
+        }
+
+        let contacts: Vec<&str> = self
+            .config
+            .acme_contacts
+            .iter()
+            .map(String::as_str)
+            .collect();
+        let new_account = NewAccount {
+            contact: &contacts,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        };
+        let (account, credentials) = Account::create(&new_account, directory_url, None).await?;
+
+        let stored = AcmeAccount {
+            directory_url: directory_url.clone(),
+            credentials: serde_json::to_string(&credentials)?,
+        };
+        self.database.transact(async |trx| stored.put(trx)).await?;
+        debug!("created ACME account with {}", directory_url);
+
+        Ok(account)
+    }
+
+    async fn issue(&self) -> Result<TlsCert> {
+        let account = self.account().await?;
+        let identifiers: Vec<Identifier> = self
+            .config
+            .acme_domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect();
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &identifiers,
+            })
+            .await?;
+
+        let result = self.complete_order(&mut order).await;
+        self.challenges.clear();
+
+        result
+    }
+
+    async fn complete_order(&self, order: &mut Order) -> Result<TlsCert> {
+        let challenge_type = self.config.acme_challenge.challenge_type();
+
+        for authorization in order.authorizations().await? {
+            // Authorizations stay valid for a while and are reused by the CA.
+            if authorization.status == AuthorizationStatus::Valid {
+                continue;
+            }
+            let Identifier::Dns(domain) = &authorization.identifier;
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.r#type == challenge_type)
+                .with_context(|| {
+                    format!(
+                        "CA offered no {:?} challenge for {}",
+                        challenge_type, domain
+                    )
+                })?;
+
+            let key_authorization = order.key_authorization(challenge);
+            self.challenges.add(
+                self.config.acme_challenge,
+                domain,
+                &challenge.token,
+                &key_authorization,
+            )?;
+            order.set_challenge_ready(&challenge.url).await?;
+        }
+        wait_for_order(order, OrderStatus::Ready).await?;
+
+        let key_pair = KeyPair::generate()?;
+        let mut params = CertificateParams::new(self.config.acme_domains.clone())?;
+        params.distinguished_name = DistinguishedName::new();
+        let csr = params.serialize_request(&key_pair)?;
+        order.finalize(csr.der()).await?;
+        wait_for_order(order, OrderStatus::Valid).await?;
+
+        let pub_cert_chain = order
+            .certificate()
+            .await?
+            .context("CA returned no certificate for a valid order")?;
+
+        let now = DateTime::now();
+        let mut tls_cert = TlsCert {
+            domains: self.config.acme_domains.clone(),
+            last_renewed: now,
+            renew_at: now,
+            priv_key: key_pair.serialize_der(),
+            pub_cert_chain: pub_cert_chain.into_bytes(),
+        };
+        tls_cert.renew_at = RustlsConfig::try_from(tls_cert.tls_config()?)?.renew_after();
+
+        Ok(tls_cert)
+    }
+}
+
+/// Polls the order until it reaches `status`, which comes after every
+/// status it passes through.
+async fn wait_for_order(order: &mut Order, status: OrderStatus) -> Result<()> {
+    for _ in 0..MAX_POLLS {
+        let state = order.refresh().await?;
+        match state.status {
+            current if current == status => return Ok(()),
+            OrderStatus::Invalid => {
+                return Err(anyhow!("order became invalid: {:?}", state.error));
+            }
+            // Finalizing an order that's ready doesn't wait for it to become valid.
+            OrderStatus::Valid => return Ok(()),
+            _ => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+
+    bail!("order not {:?} after {} checks", status, MAX_POLLS)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::TlsConfig;
+    use crate::server::acme::{
+        tls_alpn_certificate, Challenges, ACME_TLS_ALPN, HTTP_CHALLENGE_PATH,
+    };
+    use crate::server::cert_resolver::CertResolver;
+    use crate::server::{certified_key, resolver_server_config};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::CryptoProvider;
+    use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+    use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+    use std::sync::Arc;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use tower::ServiceExt;
+
+    /// Accepts any certificate, as the CA does for TLS-ALPN-01, where only
+    /// the acmeIdentifier extension counts.
+    #[derive(Debug)]
+    struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            rustls::crypto::verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    /// Handshakes with the server on `port`, offering `alpn`, and returns the
+    /// negotiated protocol and the certificate served.
+    async fn handshake(port: u16, alpn: &[u8]) -> (Option<Vec<u8>>, CertificateDer<'static>) {
+        let builder = ClientConfig::builder();
+        let verifier = AcceptAnyCertificate(builder.crypto_provider().clone());
+        let mut client_config = builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![alpn.to_vec()];
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let (_, connection) = stream.get_ref();
+
+        (
+            connection.alpn_protocol().map(<[u8]>::to_vec),
+            connection.peer_certificates().unwrap()[0]
+                .clone()
+                .into_owned(),
+        )
+    }
+
+    #[tokio::test]
+    async fn answers_tls_alpn_challenges_in_the_handshake() {
+        let digest = [7; 32];
+        let challenges = Challenges::default();
+        challenges.lock().tls_alpn.insert(
+            "localhost".to_string(),
+            tls_alpn_certificate("localhost", &digest).unwrap(),
+        );
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let certificate = certified_key(TlsConfig {
+            tls_priv_key: Some(certificate.key_pair.serialize_der()),
+            tls_pub_cert: Some(vec![certificate.cert.der().to_vec()]),
+        })
+        .unwrap();
+        let resolver = Arc::new(CertResolver::new(
+            Some(certificate.clone()),
+            Some(challenges),
+        ));
+        // As set up by `Server::acme` for TLS-ALPN-01.
+        let mut server_config = resolver_server_config(resolver, None);
+        server_config.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+
+        let (protocol, served) = handshake(port, ACME_TLS_ALPN).await;
+        assert_eq!(protocol.as_deref(), Some(ACME_TLS_ALPN));
+        // id-pe-acmeIdentifier (1.3.6.1.5.5.7.1.31), critical, holding the
+        // digest as an OCTET STRING (RFC 8737, section 3).
+        let extension = [
+            &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f][..],
+            &[0x01, 0x01, 0xff, 0x04, 0x22, 0x04, 0x20],
+            &digest,
+        ]
+        .concat();
+        assert!(served
+            .windows(extension.len())
+            .any(|window| window == extension));
+
+        // Everyone else still gets the regular certificate.
+        let (protocol, served) = handshake(port, b"h2").await;
+        assert_eq!(protocol.as_deref(), Some(&b"h2"[..]));
+        assert_eq!(served, certificate.cert[0]);
+    }
+
+    #[tokio::test]
+    async fn serves_http_challenges() {
+        let challenges = Challenges::default();
+        challenges
+            .lock()
+            .http
+            .insert("token".to_string(), "token.thumbprint".to_string());
+
+        let request = Request::get(format!("{}/token", HTTP_CHALLENGE_PATH))
+            .body(Body::empty())
+            .unwrap();
+        let response = challenges.router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"token.thumbprint");
+
+        let request = Request::get(format!("{}/other", HTTP_CHALLENGE_PATH))
+            .body(Body::empty())
+            .unwrap();
+        let response = challenges.router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Runs against a local Pebble started with `test/config/pebble-config.json`,
+    /// e.g. from the repository root:
+    ///
+    /// ```text
+    /// docker run --rm --network host -v "$PWD/test/config:/test/config" \
+    ///     ghcr.io/letsencrypt/pebble -config /test/config/pebble-config.json
+    /// SSL_CERT_FILE=pebble.minica.pem cargo test --features rdb -- --ignored issues_certificate_from_pebble
+    /// ```
+    ///
+    /// `pebble.minica.pem` is the root of Pebble's own HTTPS certificate,
+    /// found under `test/certs/` in the Pebble repository. Pebble validates
+    /// HTTP-01 on port 5002, which this test binds on all interfaces, so the
+    /// port must be free.
+    #[cfg(feature = "rdb")]
+    #[tokio::test]
+    #[ignore = "needs a local Pebble instance"]
+    async fn issues_certificate_from_pebble() {
+        use crate::config::{AcmeChallenge, AcmeConfig};
+        use crate::database::{Database, DbModel};
+        use crate::server::acme::CertificateManager;
+        use crate::server::cert_resolver::CertResolver;
+        use crate::types::server::TlsCert;
+        use crate::types::util::DateTime;
+        use std::sync::Arc;
+
+        let database_dir =
+            std::env::temp_dir().join(format!("genny-acme-{:016x}", rand::random::<u64>()));
+        let database = Arc::new(Database::open_rocksdb(&database_dir).unwrap());
+        let challenges = Challenges::default();
+        let resolver = Arc::new(CertResolver::new(None, None));
+
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:5002").await.unwrap();
+        let router = challenges.router();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let config = AcmeConfig {
+            acme_domains: vec!["localhost".to_string()],
+            acme_directory_url: "https://localhost:14000/dir".to_string(),
+            acme_contacts: vec![],
+            acme_challenge: AcmeChallenge::Http01,
+        };
+        let manager = CertificateManager::new(config, database.clone(), resolver, challenges);
+
+        let renew_at = manager.ensure_certificate().await.unwrap();
+        assert!(renew_at > DateTime::now());
+        let key = TlsCert::key_for(&["localhost".to_string()]);
+        let stored = TlsCert::get(&database.start_trx().unwrap(), &key)
+            .await
+            .unwrap();
+        assert!(stored.is_some());
+
+        // A second call reuses the stored certificate.
+        assert_eq!(manager.ensure_certificate().await.unwrap(), renew_at);
+
+        let _ = std::fs::remove_dir_all(&database_dir);
+    }
+}
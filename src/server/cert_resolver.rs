@@ -0,0 +1,206 @@
+//! Swaps the served certificate while the listeners keep running.
+//!
+//! Every HTTPS and QUIC listener shares one [`CertResolver`], so a renewed
+//! ACME certificate or changed PEM files take effect from the next handshake
+//! on. Connections already established keep the certificate they started with.
+
+use crate::config::TlsFiles;
+use crate::server::acme::{Challenges, ACME_TLS_ALPN};
+use crate::server::certified_key;
+use crate::Result;
+use anyhow::{bail, Context};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::select;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// How often PEM files are checked for changes.
+const PEM_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Hands out the current certificate, or the TLS-ALPN-01 certificate when an
+/// ACME CA is validating.
+#[derive(Debug)]
+pub(crate) struct CertResolver {
+    certificate: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: Option<Challenges>,
+}
+
+impl CertResolver {
+    /// `challenges` are only needed for ACME with TLS-ALPN-01.
+    pub(crate) fn new(
+        certificate: Option<Arc<CertifiedKey>>,
+        challenges: Option<Challenges>,
+    ) -> Self {
+        Self {
+            certificate: RwLock::new(certificate),
+            challenges,
+        }
+    }
+
+    /// Replaces the certificate for every handshake from now on.
+    pub(crate) fn set_certificate(&self, certificate: Arc<CertifiedKey>) {
+        *self
+            .certificate
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(certificate);
+    }
+
+    pub(crate) fn certificate(&self) -> Option<Arc<CertifiedKey>> {
+        self.certificate
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if let Some(challenges) = &self.challenges {
+            let is_challenge = client_hello
+                .alpn()
+                .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+            if is_challenge {
+                return challenges.tls_alpn_certificate(client_hello.server_name()?);
+            }
+        }
+
+        // Handshakes fail until the first ACME certificate is issued.
+        self.certificate()
+    }
+}
+
+/// Loads the key and certificate from their files, checking they belong together.
+pub(crate) fn load_pem_files(tls_files: &TlsFiles) -> Result<Arc<CertifiedKey>> {
+    let certificate = certified_key(tls_files.read()?)?;
+    if certificate.keys_match().is_err() {
+        bail!("TLS private key doesn't match the public certificate");
+    }
+
+    Ok(certificate)
+}
+
+/// Reloads the certificate into a [`CertResolver`] when its PEM files change.
+pub(crate) struct PemWatcher {
+    tls_files: TlsFiles,
+    resolver: Arc<CertResolver>,
+    /// Modification times of the key and certificate last loaded.
+    loaded: Option<(SystemTime, SystemTime)>,
+}
+
+impl PemWatcher {
+    pub(crate) fn new(tls_files: TlsFiles, resolver: Arc<CertResolver>) -> Self {
+        Self {
+            tls_files,
+            resolver,
+            loaded: None,
+        }
+    }
+
+    /// Checks the files until cancelled.
+    pub(crate) async fn run(mut self, cancellation_token: CancellationToken) {
+        let mut interval = tokio::time::interval(PEM_POLL_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            match self.reload_if_changed() {
+                Ok(true) => info!("reloaded TLS certificate from files"),
+                Ok(false) => {}
+                // Most likely caught between writing the key and the
+                // certificate; the next check picks up both.
+                Err(err) => warn!(
+                    "failed to reload TLS certificate, keeping the current one: {:#}",
+                    err
+                ),
+            }
+        }
+    }
+
+    /// Loads the files if they changed since last time, returning whether they did.
+    pub(crate) fn reload_if_changed(&mut self) -> Result<bool> {
+        let priv_key_path = self.tls_files.tls_priv_key.as_deref();
+        let pub_cert_path = self.tls_files.tls_pub_cert.as_deref();
+        let modified = (
+            modified_at(priv_key_path.context("TLS private key not provided")?)?,
+            modified_at(pub_cert_path.context("TLS public certificate not provided")?)?,
+        );
+        if self.loaded == Some(modified) {
+            return Ok(false);
+        }
+
+        self.resolver
+            .set_certificate(load_pem_files(&self.tls_files)?);
+        self.loaded = Some(modified);
+
+        Ok(true)
+    }
+}
+
+fn modified_at(path: &Path) -> Result<SystemTime> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("failed to read metadata of {}", path.display()))?;
+
+    Ok(metadata.modified()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::TlsFiles;
+    use crate::server::cert_resolver::{CertResolver, PemWatcher};
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    fn write_self_signed(priv_key_path: &Path, pub_cert_path: &Path, modified: SystemTime) {
+        let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(priv_key_path, certified_key.key_pair.serialize_pem()).unwrap();
+        std::fs::write(pub_cert_path, certified_key.cert.pem()).unwrap();
+
+        for path in [priv_key_path, pub_cert_path] {
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn reloads_changed_pem_files() {
+        let dir = std::env::temp_dir().join(format!("genny-pem-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let priv_key_path = dir.join("key.pem");
+        let pub_cert_path = dir.join("cert.pem");
+        let first_written = SystemTime::now() - Duration::from_secs(60);
+        write_self_signed(&priv_key_path, &pub_cert_path, first_written);
+
+        let resolver = Arc::new(CertResolver::new(None, None));
+        let tls_files = TlsFiles {
+            tls_priv_key: Some(priv_key_path.clone()),
+            tls_pub_cert: Some(pub_cert_path.clone()),
+        };
+        let mut watcher = PemWatcher::new(tls_files, resolver.clone());
+
+        assert!(watcher.reload_if_changed().unwrap());
+        let first = resolver.certificate().unwrap();
+        assert!(!watcher.reload_if_changed().unwrap());
+
+        write_self_signed(&priv_key_path, &pub_cert_path, SystemTime::now());
+        assert!(watcher.reload_if_changed().unwrap());
+        let second = resolver.certificate().unwrap();
+        assert_ne!(first.cert, second.cert);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,393 @@
+//! The real client address behind load balancers and reverse proxies.
+//!
+//! Proxies either prepend a PROXY protocol header to the connection, which
+//! replaces the remote address of the whole connection, or add an
+//! `X-Forwarded-For` header to each request, which is only believed when the
+//! request comes from a trusted proxy. Either way handlers keep extracting
+//! `ConnectInfo<SocketAddr>`, which then holds the client's address.
+
+use crate::Result;
+use anyhow::{anyhow, bail, Context};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderName};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::{info_span, Instrument};
+
+/// How long a proxy gets to send the PROXY protocol header, so connections
+/// that never send one don't linger.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Version 1 headers are at most this long, including the CRLF.
+const PROXY_V1_MAX_LEN: usize = 107;
+
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+pub(crate) const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// An IP address range like `10.0.0.0/8`, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners see IPv4 clients as `::ffff:a.b.c.d`.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let parsed: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid IP address {}", addr))?;
+        // Networks of IPv4-mapped addresses like `::ffff:10.0.0.0/104` are
+        // stored as IPv4, with the 96 bits of the mapping taken off the prefix.
+        let addr = parsed.to_canonical();
+        let (max_prefix_len, mapped_bits) = match (parsed, addr) {
+            (IpAddr::V4(_), _) => (32, 0),
+            (IpAddr::V6(_), IpAddr::V4(_)) => (32, 96),
+            (IpAddr::V6(_), IpAddr::V6(_)) => (128, 0),
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .and_then(|prefix_len| prefix_len.checked_sub(mapped_bits))
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .with_context(|| format!("invalid prefix length {}", prefix_len))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Reads the PROXY protocol header the proxy sends ahead of the client's
+/// bytes, returning the client's address. Health checks by the proxy itself
+/// keep `peer_addr`.
+///
+/// Reads byte by byte where needed, so nothing after the header is consumed.
+pub(crate) async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer_addr: SocketAddr,
+) -> Result<SocketAddr> {
+    let client_addr = tokio::time::timeout(PROXY_HEADER_TIMEOUT, async {
+        let mut prefix = [0; 8];
+        stream.read_exact(&mut prefix).await?;
+
+        if prefix.starts_with(b"PROXY ") {
+            read_proxy_v1(stream, prefix).await
+        } else if prefix == PROXY_V2_SIGNATURE[..8] {
+            read_proxy_v2(stream).await
+        } else {
+            bail!("missing PROXY protocol header")
+        }
+    })
+    .await
+    .context("timed out waiting for the PROXY protocol header")??;
+
+    Ok(client_addr.unwrap_or(peer_addr))
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`, or `PROXY UNKNOWN`.
+async fn read_proxy_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    prefix: [u8; 8],
+) -> Result<Option<SocketAddr>> {
+    let mut header = prefix.to_vec();
+    while !header.ends_with(b"\r\n") {
+        if header.len() == PROXY_V1_MAX_LEN {
+            bail!("PROXY protocol header too long");
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    let header = std::str::from_utf8(&header[..header.len() - 2])?;
+    let parts: Vec<&str> = header.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src_addr, _, src_port, _] => {
+            let ip: IpAddr = src_addr.parse()?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                bail!("{} header with address {}", protocol, ip);
+            }
+            let port: u16 = src_port.parse()?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("invalid PROXY protocol header: {}", header)),
+    }
+}
+
+/// The binary header: the rest of the signature, version and command,
+/// address family, length and the addresses, followed by TLVs that are
+/// skipped.
+async fn read_proxy_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut fixed = [0; 8];
+    stream.read_exact(&mut fixed).await?;
+    if fixed[..4] != PROXY_V2_SIGNATURE[8..] {
+        bail!("invalid PROXY protocol v2 signature");
+    }
+    let [_, _, _, _, version_command, family, len_high, len_low] = fixed;
+    if version_command >> 4 != 2 {
+        bail!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+
+    let mut addresses = vec![0; usize::from(u16::from_be_bytes([len_high, len_low]))];
+    stream.read_exact(&mut addresses).await?;
+
+    // LOCAL: a health check by the proxy itself.
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+
+    let client_addr = match family >> 4 {
+        // AF_INET: source address, destination address, source port, destination port.
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        0x1 | 0x2 => bail!("PROXY protocol v2 addresses truncated"),
+        // AF_UNSPEC and AF_UNIX have no address to give.
+        _ => None,
+    };
+
+    Ok(client_addr)
+}
+
+/// The client's address from `X-Forwarded-For`, if `peer_addr` is one of
+/// `trusted_proxies`. Forwarded addresses have no port, so it's 0 unless the
+/// proxy included one.
+pub(crate) fn forwarded_client_addr(
+    headers: &HeaderMap,
+    peer_addr: SocketAddr,
+    trusted_proxies: &[IpNetwork],
+) -> SocketAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+    if !is_trusted(peer_addr.ip()) {
+        return peer_addr;
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    // Each proxy appends the address it got the request from, so the client
+    // is the last address not added by a trusted proxy. Anything before it
+    // is up to the client and can't be believed.
+    let mut client_addr = peer_addr;
+    for addr in forwarded.into_iter().rev() {
+        let Some(addr) = addr
+            .parse::<SocketAddr>()
+            .ok()
+            .or_else(|| addr.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0)))
+        else {
+            break;
+        };
+        client_addr = addr;
+        if !is_trusted(addr.ip()) {
+            break;
+        }
+    }
+
+    client_addr
+}
+
+/// Replaces `ConnectInfo` with the client's address from `X-Forwarded-For`
+/// on requests through a trusted proxy, and logs them under a span with it.
+pub(crate) async fn resolve_forwarded_client(
+    State(trusted_proxies): State<Arc<[IpNetwork]>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(ConnectInfo(peer_addr)) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .copied()
+    else {
+        return next.run(request).await;
+    };
+
+    let client_addr = forwarded_client_addr(request.headers(), peer_addr, &trusted_proxies);
+    if client_addr == peer_addr {
+        return next.run(request).await;
+    }
+
+    request.extensions_mut().insert(ConnectInfo(client_addr));
+    // Connections from a proxy carry requests of many clients, so this is
+    // per request rather than on the connection span.
+    next.run(request)
+        .instrument(info_span!("forwarded", "remote" = client_addr.to_string()))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::client_addr::{
+        forwarded_client_addr, read_proxy_header, IpNetwork, PROXY_V2_SIGNATURE, X_FORWARDED_FOR,
+    };
+    use axum::http::{HeaderMap, HeaderValue};
+    use std::net::SocketAddr;
+
+    const PEER_ADDR: SocketAddr = SocketAddr::new(
+        std::net::IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2)),
+        40000,
+    );
+
+    #[tokio::test]
+    async fn reads_proxy_v1_headers() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 443\r\nGET /";
+        let client_addr = read_proxy_header(&mut stream, PEER_ADDR).await.unwrap();
+        assert_eq!(client_addr, "192.0.2.1:56324".parse().unwrap());
+        // The request itself is left for HTTP.
+        assert_eq!(stream, b"GET /");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        let client_addr = read_proxy_header(&mut stream, PEER_ADDR).await.unwrap();
+        assert_eq!(client_addr, PEER_ADDR);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_proxy_header(&mut stream, PEER_ADDR).await.is_err());
+
+        // The address has to match the protocol.
+        let mut stream: &[u8] = b"PROXY TCP4 2001:db8::1 10.0.0.1 56324 443\r\n";
+        assert!(read_proxy_header(&mut stream, PEER_ADDR).await.is_err());
+        let mut stream: &[u8] = b"PROXY TCP6 192.0.2.1 ::1 56324 443\r\n";
+        assert!(read_proxy_header(&mut stream, PEER_ADDR).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_proxy_v2_headers() {
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        // PROXY command, TCP over IPv6, addresses and a 3 byte TLV.
+        header.extend_from_slice(&[0x21, 0x21, 0, 39]);
+        header.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        header.extend_from_slice(&[0; 16]);
+        header.extend_from_slice(&8080_u16.to_be_bytes());
+        header.extend_from_slice(&443_u16.to_be_bytes());
+        header.extend_from_slice(&[0x04, 0, 0]);
+        header.extend_from_slice(b"GET /");
+
+        let mut stream = header.as_slice();
+        let client_addr = read_proxy_header(&mut stream, PEER_ADDR).await.unwrap();
+        assert_eq!(client_addr, "[2001:db8::1]:8080".parse().unwrap());
+        assert_eq!(stream, b"GET /");
+
+        let mut local = PROXY_V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let client_addr = read_proxy_header(&mut local.as_slice(), PEER_ADDR)
+            .await
+            .unwrap();
+        assert_eq!(client_addr, PEER_ADDR);
+    }
+
+    #[test]
+    fn matches_ip_networks() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+
+        let network: IpNetwork = "::1".parse().unwrap();
+        assert!(network.contains("::1".parse().unwrap()));
+        assert!(!network.contains("::2".parse().unwrap()));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains("192.0.2.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_networks() {
+        let network: IpNetwork = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(network, "10.0.0.0/8".parse().unwrap());
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("192.0.2.1".parse().unwrap()));
+
+        assert_eq!(
+            "::ffff:192.0.2.1".parse::<IpNetwork>().unwrap(),
+            "192.0.2.1/32".parse().unwrap()
+        );
+        // Wider than the mapped range, or past the end of it.
+        assert!("::ffff:10.0.0.0/64".parse::<IpNetwork>().is_err());
+        assert!("::ffff:10.0.0.0/129".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn only_trusted_proxies_forward_addresses() {
+        let trusted_proxies: Vec<IpNetwork> = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("203.0.113.9, 198.51.100.7, 10.0.0.3"),
+        );
+
+        // The spoofable leftmost address is skipped for the one the first
+        // trusted proxy saw.
+        assert_eq!(
+            forwarded_client_addr(&headers, PEER_ADDR, &trusted_proxies),
+            "198.51.100.7:0".parse().unwrap()
+        );
+
+        let untrusted_peer: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        assert_eq!(
+            forwarded_client_addr(&headers, untrusted_peer, &trusted_proxies),
+            untrusted_peer
+        );
+
+        assert_eq!(
+            forwarded_client_addr(&HeaderMap::new(), PEER_ADDR, &trusted_proxies),
+            PEER_ADDR
+        );
+    }
+}
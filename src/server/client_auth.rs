@@ -0,0 +1,119 @@
+//! Optional client certificate authentication, required by the admin
+//! endpoints only.
+//!
+//! Client certificates are requested but not required during the handshake,
+//! so Discord and browsers connect as before. A verified certificate is put
+//! into the request extensions as a [`ClientIdentity`], which the admin
+//! routes insist on.
+
+use crate::Result;
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use rustls_pki_types::CertificateDer;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Where the admin endpoints are nested.
+pub(crate) const ADMIN_PATH: &str = "/admin";
+
+/// The client certificate verified during the TLS handshake.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientIdentity {
+    /// The subject, e.g. `CN=ops`.
+    pub(crate) subject: String,
+    pub(crate) certificate: CertificateDer<'static>,
+}
+
+impl ClientIdentity {
+    /// The identity of the first of `certificates`, which rustls only hands
+    /// out once they have been verified.
+    pub(crate) fn from_peer_certificates(
+        certificates: Option<&[CertificateDer<'_>]>,
+    ) -> Option<Self> {
+        let certificate = certificates?.first()?.clone().into_owned();
+        let subject = match x509_parser::parse_x509_certificate(&certificate) {
+            Ok((_, parsed)) => parsed.subject().to_string(),
+            Err(err) => {
+                debug!("failed to parse client certificate: {}", err);
+                return None;
+            }
+        };
+
+        Some(Self {
+            subject,
+            certificate,
+        })
+    }
+}
+
+/// Verifies client certificates against `ca_bundle`, letting clients
+/// without one connect anyway.
+pub(crate) fn client_verifier(ca_bundle: Vec<Vec<u8>>) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for ca_cert in ca_bundle {
+        roots.add(CertificateDer::from(ca_cert))?;
+    }
+
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()?;
+
+    Ok(verifier)
+}
+
+/// Rejects requests made without a verified client certificate.
+pub(crate) async fn require_client_identity(request: Request, next: Next) -> Response {
+    match request.extensions().get::<ClientIdentity>() {
+        Some(client_identity) => {
+            debug!("admin request from {}", client_identity.subject);
+            next.run(request).await
+        }
+        None => (StatusCode::FORBIDDEN, "client certificate required").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::client_auth::{require_client_identity, ClientIdentity};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::routing::get;
+    use axum::{middleware, Extension, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn admin_routes_need_a_client_identity() {
+        let certified_key = rcgen::generate_simple_self_signed(vec!["ops".into()]).unwrap();
+        let certificates = [certified_key.cert.der().clone()];
+        let client_identity =
+            ClientIdentity::from_peer_certificates(Some(&certificates[..])).unwrap();
+        assert!(client_identity
+            .subject
+            .contains("CN=rcgen self signed cert"));
+
+        let router = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(client_identity): Extension<ClientIdentity>| async move {
+                        client_identity.subject
+                    },
+                ),
+            )
+            .layer(middleware::from_fn(require_client_identity));
+
+        let anonymous = Request::get("/").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(anonymous).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let mut authenticated = Request::get("/").body(Body::empty()).unwrap();
+        authenticated.extensions_mut().insert(client_identity);
+        let response = router.oneshot(authenticated).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,273 @@
+//! Listening sockets besides plain TCP: Unix domain sockets, e.g. behind a
+//! reverse proxy, and sockets handed over by systemd socket activation.
+
+use crate::Result;
+use anyhow::Context;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use {
+    std::path::{Path, PathBuf},
+    tokio::net::{UnixListener, UnixStream},
+};
+
+/// The remote address given to connections over Unix sockets, whose peers
+/// are on this host but have no IP address.
+pub(crate) const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// The name systemd gives sockets to serve HTTPS on, set with
+/// `FileDescriptorName=https`. Sockets with any other name serve plain HTTP.
+pub(crate) const SYSTEMD_HTTPS_NAME: &str = "https";
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub(crate) async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), remote_addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), UNIX_PEER_ADDR))
+            }
+        }
+    }
+
+    /// The port TCP listeners are bound to.
+    pub(crate) fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    /// The address or socket path, for logging.
+    pub(crate) fn bind_address(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|_| "unknown address".to_string()),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection accepted by a [`Listener`].
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub(crate) fn bind_tcp_listeners(socket_addrs: impl ToSocketAddrs) -> Result<Vec<Listener>> {
+    let mut listeners = vec![];
+
+    for socket_addr in socket_addrs.to_socket_addrs()? {
+        let std_listener = std::net::TcpListener::bind(socket_addr)?;
+        std_listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(std_listener).context("unable to bind socket")?;
+
+        listeners.push(Listener::Tcp(listener));
+    }
+
+    Ok(listeners)
+}
+
+/// Listens on a Unix socket at `path`, replacing a socket left behind by a
+/// previous run. Fails if another process still listens on it.
+#[cfg(unix)]
+pub(crate) fn bind_unix_listener(path: &Path) -> Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            // Only a socket nobody listens on refuses connections.
+            match std::os::unix::net::UnixStream::connect(path) {
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path).with_context(|| {
+                        format!("unable to remove stale socket {}", path.display())
+                    })?;
+                }
+                _ => {
+                    return Err(io::Error::from(io::ErrorKind::AddrInUse))
+                        .with_context(|| format!("Unix socket {} is in use", path.display()));
+                }
+            }
+        }
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("unable to bind Unix socket {}", path.display()))?;
+
+    Ok(Listener::Unix(listener, path.to_path_buf()))
+}
+
+/// Takes the listening sockets passed by systemd socket activation, with the
+/// names set by `FileDescriptorName=`. Empty unless the process was started
+/// by systemd with sockets, or when they were taken already.
+#[cfg(unix)]
+pub(crate) fn take_systemd_listeners() -> Result<Vec<(Option<String>, Listener)>> {
+    use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+    use std::sync::OnceLock;
+
+    /// The first descriptor passed, after stdin, stdout and stderr.
+    const SD_LISTEN_FDS_START: RawFd = 3;
+    /// Set by the first call. The environment is left alone, since changing
+    /// it while other threads run is unsound.
+    static TAKEN: OnceLock<()> = OnceLock::new();
+
+    if TAKEN.set(()).is_err() {
+        return Ok(vec![]);
+    }
+    // LISTEN_PID keeps child processes, which inherit the variables, from
+    // taking descriptors that aren't theirs.
+    let for_this_process = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    if !for_this_process {
+        return Ok(vec![]);
+    }
+    let fd_count: RawFd = std::env::var("LISTEN_FDS")
+        .context("LISTEN_FDS not set")?
+        .parse()
+        .context("invalid LISTEN_FDS")?;
+    let names: Vec<String> = std::env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+
+    let mut listeners = vec![];
+    for (index, fd) in (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fd_count).enumerate() {
+        // SAFETY: systemd opened these descriptors for this process, and
+        // they're only taken once thanks to `TAKEN`.
+        let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // Only sockets with an IP address have a local address as TCP.
+        let tcp_listener = std::net::TcpListener::from(owned_fd);
+        let listener = if tcp_listener.local_addr().is_ok() {
+            tcp_listener.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(tcp_listener)?)
+        } else {
+            let unix_listener = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp_listener));
+            let path = unix_listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(Path::to_path_buf))
+                .unwrap_or_else(|| PathBuf::from(format!("fd/{}", fd)));
+            unix_listener.set_nonblocking(true)?;
+            Listener::Unix(UnixListener::from_std(unix_listener)?, path)
+        };
+
+        listeners.push((names.get(index).cloned(), listener));
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::server::listener::{bind_unix_listener, UNIX_PEER_ADDR};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn accepts_unix_connections() {
+        let path = std::env::temp_dir().join(format!("genny-{:016x}.sock", rand::random::<u64>()));
+        // Binding again replaces the socket left behind.
+        drop(bind_unix_listener(&path).unwrap());
+        let listener = bind_unix_listener(&path).unwrap();
+        assert!(listener.bind_address().starts_with("unix:"));
+        assert_eq!(listener.port(), None);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut stream, remote_addr) = listener.accept().await.unwrap();
+        assert_eq!(remote_addr, UNIX_PEER_ADDR);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // But not one that's still in use.
+        assert!(bind_unix_listener(&path).is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
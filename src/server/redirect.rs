@@ -0,0 +1,136 @@
+//! Moves plain HTTP clients over to HTTPS and keeps them there.
+
+use crate::server::acme::Challenges;
+use axum::extract::State;
+use axum::http::header::HOST;
+use axum::http::uri::Authority;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+
+/// A year, the minimum for HSTS preload lists.
+pub(crate) const DEFAULT_HSTS_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Redirects every request to HTTPS on `https_port`, except ACME HTTP-01
+/// challenges, which the CA only ever requests over plain HTTP.
+pub(crate) fn https_redirect_router(https_port: u16, challenges: Option<&Challenges>) -> Router {
+    let redirect = Router::new()
+        .fallback(redirect_to_https)
+        .with_state(https_port);
+
+    match challenges {
+        Some(challenges) => challenges.router().merge(redirect),
+        None => redirect,
+    }
+}
+
+/// The `Strict-Transport-Security` value, or `None` if `max_age_secs` is 0.
+pub(crate) fn hsts_header(max_age_secs: u64, include_subdomains: bool) -> Option<HeaderValue> {
+    if max_age_secs == 0 {
+        return None;
+    }
+
+    let mut value = format!("max-age={}", max_age_secs);
+    if include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+
+    HeaderValue::from_str(&value).ok()
+}
+
+async fn redirect_to_https(
+    State(https_port): State<u16>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let host = uri
+        .authority()
+        .map(Authority::as_str)
+        .or_else(|| headers.get(HOST).and_then(|host| host.to_str().ok()));
+
+    match host.and_then(|host| https_url(host, https_port, &uri)) {
+        // 308 rather than 301, so POSTs stay POSTs.
+        Some(url) => Redirect::permanent(&url).into_response(),
+        None => (StatusCode::BAD_REQUEST, "missing or invalid Host header").into_response(),
+    }
+}
+
+/// The HTTPS URL for a request to `host` with `uri`, keeping the path and
+/// query but replacing the port.
+fn https_url(host: &str, https_port: u16, uri: &Uri) -> Option<String> {
+    let authority: Authority = host.parse().ok()?;
+    let path_and_query = uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+
+    let url = match https_port {
+        443 => format!("https://{}{}", authority.host(), path_and_query),
+        port => format!("https://{}:{}{}", authority.host(), port, path_and_query),
+    };
+
+    Some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::acme::{Challenges, HTTP_CHALLENGE_PATH};
+    use crate::server::redirect::{hsts_header, https_redirect_router, https_url};
+    use axum::body::Body;
+    use axum::http::header::{HOST, LOCATION};
+    use axum::http::{Request, StatusCode, Uri};
+    use tower::ServiceExt;
+
+    #[test]
+    fn builds_https_urls() {
+        let uri = Uri::from_static("/interactions?a=1");
+
+        assert_eq!(
+            https_url("example.com:80", 443, &uri).as_deref(),
+            Some("https://example.com/interactions?a=1")
+        );
+        assert_eq!(
+            https_url("example.com", 8443, &uri).as_deref(),
+            Some("https://example.com:8443/interactions?a=1")
+        );
+        assert_eq!(
+            https_url("[::1]:8080", 443, &Uri::from_static("/")).as_deref(),
+            Some("https://[::1]/")
+        );
+        assert_eq!(https_url("bad host", 443, &uri), None);
+    }
+
+    #[test]
+    fn hsts_can_be_disabled() {
+        assert_eq!(hsts_header(0, true), None);
+        assert_eq!(
+            hsts_header(60, true).unwrap(),
+            "max-age=60; includeSubDomains"
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_everything_but_acme_challenges() {
+        let challenges = Challenges::default();
+        let router = https_redirect_router(443, Some(&challenges));
+
+        let request = Request::post("/interactions")
+            .header(HOST, "example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "https://example.com/interactions"
+        );
+
+        // Unknown tokens are a 404 from the challenge route, not a redirect.
+        let request = Request::get(format!("{}/token", HTTP_CHALLENGE_PATH))
+            .header(HOST, "example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
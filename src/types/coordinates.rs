@@ -1,5 +1,6 @@
 use rkyv::{Archive, Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 #[derive(Clone, Copy, Archive, Serialize, Deserialize, PartialEq, Eq, Debug)]
 #[repr(u8)]
@@ -21,6 +22,18 @@ impl Display for ArkMap {
     }
 }
 
+impl FromStr for ArkMap {
+    type Err = anyhow::Error;
+
+    /// Parses the variant names used as Discord command choice values.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ArkMap::ALL
+            .into_iter()
+            .find(|map| map.choice_value() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown map: {}", s))
+    }
+}
+
 #[derive(Clone, Copy)]
 pub(crate) struct ArkMapScale {
     pub(crate) latitude_origin: i32,
@@ -51,6 +64,10 @@ pub(crate) struct ArkCoordinates {
 }
 
 impl UE4Coordinates {
+    pub(crate) fn map(&self) -> ArkMap {
+        self.map
+    }
+
     pub(crate) fn within_range(&self, other: &Self, range: i32) -> WithinRange {
         if self.map != other.map {
             // They are not even on the same map.
@@ -97,6 +114,22 @@ impl UE4Coordinates {
 }
 
 impl ArkCoordinates {
+    pub(crate) fn new(latitude: f32, longitude: f32, map: ArkMap) -> Self {
+        Self {
+            latitude,
+            longitude,
+            map,
+        }
+    }
+
+    pub(crate) fn latitude(&self) -> f32 {
+        self.latitude
+    }
+
+    pub(crate) fn longitude(&self) -> f32 {
+        self.longitude
+    }
+
     /// Returns ARK Coordinates that are rounded to one decimal place, just like in ARK.
     pub(crate) fn rounded(&self) -> Self {
         Self {
@@ -108,6 +141,23 @@ impl ArkCoordinates {
 }
 
 impl ArkMap {
+    pub(crate) const ALL: [ArkMap; 4] = [
+        ArkMap::Island,
+        ArkMap::ScorchedEarth,
+        ArkMap::Center,
+        ArkMap::Aberration,
+    ];
+
+    /// The value used for this map in Discord command choices.
+    pub(crate) const fn choice_value(&self) -> &'static str {
+        match self {
+            ArkMap::Island => "Island",
+            ArkMap::ScorchedEarth => "ScorchedEarth",
+            ArkMap::Center => "Center",
+            ArkMap::Aberration => "Aberration",
+        }
+    }
+
     /// Provides the origin and scale values used by coordinate calculations.
     ///
     /// How the values are gathered:
@@ -1,6 +1,10 @@
+use crate::types::util::DateTime;
 use rkyv::{Archive, Deserialize, Serialize};
 use std::time::Duration;
 
+/// Element shards needed to match the power duration of a single raw element.
+const SHARDS_PER_ELEMENT: u64 = 100;
+
 pub(crate) trait FuelItem {
     fn lasts_until(&self, duration_secs: u64) -> Duration;
 }
@@ -9,7 +13,40 @@ pub(crate) trait FuelItem {
 #[derive(Archive, Deserialize, Serialize)]
 pub(crate) struct Fuel<I: FuelItem, const DURATION_SECS: u64> {
     fuel: I,
-    last_filled: u64,
+    last_filled: DateTime,
+}
+
+impl<I: FuelItem, const DURATION_SECS: u64> Fuel<I, DURATION_SECS> {
+    pub(crate) fn new(fuel: I, last_filled: DateTime) -> Self {
+        Self { fuel, last_filled }
+    }
+
+    pub(crate) fn last_filled(&self) -> DateTime {
+        self.last_filled
+    }
+
+    /// How long the fuel lasts from when it was last filled.
+    pub(crate) fn total_duration(&self) -> Duration {
+        self.fuel.lasts_until(DURATION_SECS)
+    }
+
+    pub(crate) fn runs_out_at(&self) -> DateTime {
+        self.last_filled.saturating_add(self.total_duration())
+    }
+
+    pub(crate) fn remaining(&self, now: &DateTime) -> Duration {
+        self.runs_out_at().duration_since(now)
+    }
+
+    /// Returns the remaining fuel as a fraction between 0 and 1.
+    pub(crate) fn fraction_left(&self, now: &DateTime) -> f32 {
+        let total = self.total_duration();
+        if total.is_zero() {
+            return 0.0;
+        }
+
+        (self.remaining(now).as_secs_f32() / total.as_secs_f32()).clamp(0.0, 1.0)
+    }
 }
 
 #[derive(Archive, Deserialize, Serialize)]
@@ -18,10 +55,26 @@ pub(crate) struct ElementOrShards {
     element_shards: ElementShard,
 }
 
+impl ElementOrShards {
+    pub(crate) fn new(raw_element_count: u32, element_shard_count: u32) -> Self {
+        Self {
+            raw_element: RawElement {
+                count: raw_element_count,
+            },
+            element_shards: ElementShard {
+                count: element_shard_count,
+            },
+        }
+    }
+}
+
 /// DURATION_SECS refer to a single raw element
 impl FuelItem for ElementOrShards {
     fn lasts_until(&self, duration_secs: u64) -> Duration {
-        todo!()
+        self.raw_element.lasts_until(duration_secs)
+            + self
+                .element_shards
+                .lasts_until(duration_secs / SHARDS_PER_ELEMENT)
     }
 }
 
@@ -51,3 +104,35 @@ impl FuelItem for ElementShard {
 pub(crate) struct Gasoline {
     count: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::types::fuel::{ElementOrShards, Fuel};
+    use crate::types::util::DateTime;
+    use std::time::Duration;
+
+    /// One raw element and 50 element shards in a Tek generator at minimum range.
+    fn tek_fuel(last_filled: DateTime) -> Fuel<ElementOrShards, 64800> {
+        Fuel::new(ElementOrShards::new(1, 50), last_filled)
+    }
+
+    #[test]
+    fn element_and_shards_duration() {
+        let fuel = tek_fuel(DateTime::from(0));
+        assert_eq!(fuel.total_duration(), Duration::from_secs(64800 + 50 * 648));
+    }
+
+    #[test]
+    fn remaining_fuel() {
+        let fuel = tek_fuel(DateTime::from(0));
+        let total = fuel.total_duration();
+
+        let halfway = DateTime::from(0).saturating_add(total / 2);
+        assert_eq!(fuel.remaining(&halfway), total / 2);
+        assert_eq!(fuel.fraction_left(&halfway), 0.5);
+
+        let long_after = fuel.runs_out_at().saturating_add(Duration::from_secs(60));
+        assert!(fuel.remaining(&long_after).is_zero());
+        assert_eq!(fuel.fraction_left(&long_after), 0.0);
+    }
+}
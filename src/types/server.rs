@@ -1,8 +1,49 @@
-struct TlsCert {
-    last_renewed: u64,
-    renew_at: u64,
-    priv_key: Vec<u8>,
-    pub_cert_chain: Vec<u8>,
+use crate::config::TlsConfig;
+use crate::types::util::DateTime;
+use crate::Result;
+use anyhow::anyhow;
+use rkyv::{Archive, Deserialize, Serialize};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::CertificateDer;
+
+/// A certificate issued over ACME, keyed by the domains it covers.
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct TlsCert {
+    pub(crate) domains: Vec<String>,
+    pub(crate) last_renewed: DateTime,
+    pub(crate) renew_at: DateTime,
+    /// PKCS#8 DER.
+    pub(crate) priv_key: Vec<u8>,
+    /// PEM, leaf certificate first, as returned by the CA.
+    pub(crate) pub_cert_chain: Vec<u8>,
+}
+
+impl TlsCert {
+    /// The key under which the certificate for `domains` is stored.
+    pub(crate) fn key_for(domains: &[String]) -> Vec<u8> {
+        domains.join(",").into_bytes()
+    }
+
+    pub(crate) fn tls_config(&self) -> Result<TlsConfig> {
+        let mut cert_chain = vec![];
+        for cert in CertificateDer::pem_slice_iter(&self.pub_cert_chain) {
+            let cert = cert.map_err(|err| anyhow!("invalid certificate in chain: {}", err))?;
+            cert_chain.push(cert.to_vec());
+        }
+
+        Ok(TlsConfig {
+            tls_priv_key: Some(self.priv_key.clone()),
+            tls_pub_cert: Some(cert_chain),
+        })
+    }
+}
+
+/// An account with an ACME CA, reused for every order placed with it.
+#[derive(Archive, Serialize, Deserialize)]
+pub(crate) struct AcmeAccount {
+    pub(crate) directory_url: String,
+    /// The account credentials as JSON, including the account key.
+    pub(crate) credentials: String,
 }
 
 pub(crate) struct ServerSettings {}
@@ -1,8 +1,11 @@
-use super::coordinates::UE4Coordinates;
+use super::coordinates::{ArkMap, UE4Coordinates};
 use crate::types::fuel::{ElementOrShards, Fuel};
-use clap::builder::Str;
 use rkyv::{Archive, Deserialize, Serialize};
 
+/// How far a Tek generator powers structures, in UE4 units. This is about
+/// 25 foundations at the default range multiplier.
+const TEK_GENERATOR_RANGE: i32 = 7500;
+
 pub(crate) trait TrackedStructure {
     fn coords(&self) -> UE4Coordinates;
 }
@@ -14,7 +17,65 @@ pub(crate) trait Generator {
 #[derive(Archive, Serialize, Deserialize)]
 pub(crate) struct TekGenerator {
     id: u64,
+    guild_id: u64,
+    server: String,
     name: String,
     coordinates: UE4Coordinates,
     current_fuel: Fuel<ElementOrShards, 64800>,
 }
+
+impl TekGenerator {
+    pub(crate) fn new(
+        id: u64,
+        guild_id: u64,
+        server: String,
+        name: String,
+        coordinates: UE4Coordinates,
+        current_fuel: Fuel<ElementOrShards, 64800>,
+    ) -> Self {
+        Self {
+            id,
+            guild_id,
+            server,
+            name,
+            coordinates,
+            current_fuel,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn guild_id(&self) -> u64 {
+        self.guild_id
+    }
+
+    pub(crate) fn server(&self) -> &str {
+        &self.server
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn map(&self) -> ArkMap {
+        self.coordinates.map()
+    }
+
+    pub(crate) fn current_fuel(&self) -> &Fuel<ElementOrShards, 64800> {
+        &self.current_fuel
+    }
+}
+
+impl Generator for TekGenerator {
+    fn range(&self) -> i32 {
+        TEK_GENERATOR_RANGE
+    }
+}
+
+impl TrackedStructure for TekGenerator {
+    fn coords(&self) -> UE4Coordinates {
+        self.coordinates
+    }
+}
@@ -1,13 +1,52 @@
 use rkyv::{Archive, Deserialize, Serialize};
-use std::time::{SystemTime, SystemTimeError};
+use std::time::{Duration, SystemTime, SystemTimeError};
 
 /// A wrapper for a signed 64-bit integer representing milliseconds
 /// from the Unix epoch.
-#[derive(Archive, Serialize, Deserialize, PartialOrd, PartialEq, Ord, Eq)]
+#[derive(Archive, Serialize, Deserialize, PartialOrd, PartialEq, Ord, Eq, Clone, Copy, Debug)]
 pub(crate) struct DateTime {
     timestamp: i64,
 }
 
+impl DateTime {
+    pub(crate) fn now() -> Self {
+        // A system clock set before the Unix epoch is treated as the epoch itself.
+        Self::try_from(SystemTime::now()).unwrap_or(Self { timestamp: 0 })
+    }
+
+    pub(crate) fn timestamp_millis(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Seconds from the Unix epoch, as used by Discord's `<t:...>` markdown.
+    pub(crate) fn timestamp_secs(&self) -> i64 {
+        self.timestamp.div_euclid(1000)
+    }
+
+    pub(crate) fn saturating_add(&self, duration: Duration) -> Self {
+        let millis = i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+
+        Self {
+            timestamp: self.timestamp.saturating_add(millis),
+        }
+    }
+
+    pub(crate) fn saturating_sub(&self, duration: Duration) -> Self {
+        let millis = i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+
+        Self {
+            timestamp: self.timestamp.saturating_sub(millis),
+        }
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    pub(crate) fn duration_since(&self, earlier: &Self) -> Duration {
+        let millis = self.timestamp.saturating_sub(earlier.timestamp).max(0);
+
+        Duration::from_millis(millis as u64)
+    }
+}
+
 impl From<i64> for DateTime {
     fn from(timestamp: i64) -> Self {
         Self { timestamp }